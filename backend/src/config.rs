@@ -1,9 +1,61 @@
+use std::path::PathBuf;
+
 use serde::Deserialize;
 
+use crate::models::{
+    DownloadQuota, Heartbeat, OverflowPolicy, SendQueuePolicy, DEFAULT_EXPORT_MAX_AGE,
+};
+use crate::purge::PurgePolicy;
+
 fn default_port() -> u16 {
     5800
 }
 
+fn default_session_idle_timeout_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_session_max_lifetime_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_session_purge_interval_secs() -> u64 {
+    10 * 60
+}
+
+fn default_state_snapshot_interval_secs() -> u64 {
+    30
+}
+
+fn default_ws_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_ws_pong_timeout_secs() -> u64 {
+    10
+}
+
+fn default_ws_send_queue_capacity() -> usize {
+    64
+}
+
+fn default_export_max_age_secs() -> u64 {
+    DEFAULT_EXPORT_MAX_AGE.num_seconds() as u64
+}
+
+/// How download tokens are tied to the first machine that uses them.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MachineBinding {
+    /// Fingerprints are ignored.
+    #[default]
+    Off,
+    /// Uses from another machine are delivered, but flagged.
+    Flag,
+    /// Uses from another machine are rejected.
+    Enforce,
+}
+
 /// Railway-specific configuration parsed from environment variables.
 #[derive(Deserialize, Debug, Default)]
 pub struct RailwayConfig {
@@ -58,6 +110,69 @@ pub struct Config {
     #[serde(default = "default_port")]
     pub port: u16,
 
+    /// How long a download token stays valid, in seconds. Tokens never expire if unset.
+    pub token_lifetime_secs: Option<u64>,
+
+    /// Comma-separated secrets for signing session cookies, newest first.
+    /// Only the first signs; the rest still verify, so secrets can be rotated without logging anyone out.
+    pub session_secrets: Option<String>,
+
+    /// Whether tokens are bound to the first machine that uses them.
+    #[serde(default)]
+    pub machine_binding: MachineBinding,
+
+    /// Maximum downloads a session may hold at once. Unlimited if unset.
+    pub download_max_live: Option<usize>,
+
+    /// Maximum downloads a session may create per hour. Unlimited if unset.
+    pub download_max_per_hour: Option<usize>,
+
+    /// Maximum executable bytes served to a session over its lifetime. Unlimited if unset.
+    pub download_max_bytes: Option<u64>,
+
+    /// Sessions not seen for this many seconds are purged.
+    #[serde(default = "default_session_idle_timeout_secs")]
+    pub session_idle_timeout_secs: u64,
+
+    /// Sessions older than this many seconds are purged, even if active.
+    #[serde(default = "default_session_max_lifetime_secs")]
+    pub session_max_lifetime_secs: u64,
+
+    /// How often, in seconds, stale sessions are purged.
+    #[serde(default = "default_session_purge_interval_secs")]
+    pub session_purge_interval_secs: u64,
+
+    /// File to persist sessions and tokens to. State is kept in memory only if unset.
+    pub state_path: Option<PathBuf>,
+
+    /// How often, in seconds, the state is written to `state_path`.
+    #[serde(default = "default_state_snapshot_interval_secs")]
+    pub state_snapshot_interval_secs: u64,
+
+    /// How often, in seconds, each WebSocket connection is pinged.
+    #[serde(default = "default_ws_ping_interval_secs")]
+    pub ws_ping_interval_secs: u64,
+
+    /// How long, in seconds, a WebSocket connection may take to answer a ping before it is dropped.
+    #[serde(default = "default_ws_pong_timeout_secs")]
+    pub ws_pong_timeout_secs: u64,
+
+    /// How many messages may wait to be written to a WebSocket connection.
+    #[serde(default = "default_ws_send_queue_capacity")]
+    pub ws_send_queue_capacity: usize,
+
+    /// What happens when a WebSocket connection's send queue is full.
+    #[serde(default)]
+    pub ws_send_queue_overflow: OverflowPolicy,
+
+    /// How many proxies in front of the server append to `X-Forwarded-For`. Defaults to one on
+    /// Railway and none elsewhere, where the header is ignored.
+    pub trusted_proxy_hops: Option<usize>,
+
+    /// Session exports older than this many seconds are refused on import.
+    #[serde(default = "default_export_max_age_secs")]
+    pub export_max_age_secs: u64,
+
     #[serde(flatten)]
     pub railway: RailwayConfig,
 }
@@ -67,4 +182,72 @@ impl Config {
     pub fn bind_addr(&self) -> String {
         format!("0.0.0.0:{}", self.port)
     }
+
+    /// Returns the session cookie secrets, newest first.
+    pub fn session_secrets(&self) -> Vec<String> {
+        self.session_secrets
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|secret| secret.trim().to_string())
+            .filter(|secret| !secret.is_empty())
+            .collect()
+    }
+
+    /// Returns the policy used by the session purge task.
+    pub fn purge_policy(&self) -> PurgePolicy {
+        PurgePolicy {
+            interval: std::time::Duration::from_secs(self.session_purge_interval_secs.max(1)),
+            idle_timeout: chrono::Duration::seconds(self.session_idle_timeout_secs as i64),
+            max_lifetime: chrono::Duration::seconds(self.session_max_lifetime_secs as i64),
+        }
+    }
+
+    /// Returns how often the state is snapshotted to disk.
+    pub fn state_snapshot_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.state_snapshot_interval_secs.max(1))
+    }
+
+    /// Returns the limits on downloads each session may create.
+    pub fn download_quota(&self) -> DownloadQuota {
+        DownloadQuota {
+            max_live: self.download_max_live,
+            max_per_hour: self.download_max_per_hour,
+            max_bytes: self.download_max_bytes,
+        }
+    }
+
+    /// Returns how WebSocket connections are checked for liveness.
+    pub fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            interval: std::time::Duration::from_secs(self.ws_ping_interval_secs.max(1)),
+            timeout: std::time::Duration::from_secs(self.ws_pong_timeout_secs.max(1)),
+        }
+    }
+
+    /// Returns the limits on messages buffered for each WebSocket connection.
+    pub fn send_queue(&self) -> SendQueuePolicy {
+        SendQueuePolicy {
+            capacity: self.ws_send_queue_capacity.max(1),
+            overflow: self.ws_send_queue_overflow,
+        }
+    }
+
+    /// Returns how many `X-Forwarded-For` entries, counted from the right, were added by
+    /// trusted proxies.
+    pub fn trusted_proxy_hops(&self) -> usize {
+        self.trusted_proxy_hops
+            .unwrap_or(if self.railway.is_railway() { 1 } else { 0 })
+    }
+
+    /// Returns how old a session export may be and still be imported.
+    pub fn export_max_age(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.export_max_age_secs.min(i64::MAX as u64) as i64)
+    }
+
+    /// Returns the configured download token lifetime, if any.
+    pub fn token_lifetime(&self) -> Option<chrono::Duration> {
+        self.token_lifetime_secs
+            .map(|secs| chrono::Duration::seconds(secs as i64))
+    }
 }
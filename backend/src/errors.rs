@@ -16,4 +16,10 @@ pub enum AppError {
 
     #[error("configuration error: {message}")]
     Config { message: String },
+
+    #[error("unable to persist state at '{path}': {message}")]
+    Persistence { path: PathBuf, message: String },
+
+    #[error("invalid payload: {message}")]
+    InvalidPayload { message: String },
 }
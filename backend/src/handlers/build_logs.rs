@@ -2,11 +2,11 @@ use salvo::http::StatusCode;
 use salvo::prelude::{handler, Request, Response};
 use salvo::Depot;
 
-use crate::state::STORE;
+use crate::state::State;
 
 #[handler]
-pub async fn get_build_logs(req: &mut Request, res: &mut Response, _depot: &mut Depot) {
-    let store = STORE.lock().await;
+pub async fn get_build_logs(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let store = State::from_depot(depot);
 
     if let Some(build_logs) = &store.build_logs {
         // Use pre-computed hash for ETag
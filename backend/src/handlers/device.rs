@@ -0,0 +1,86 @@
+use salvo::http::StatusCode;
+use salvo::prelude::{handler, Request, Response};
+use salvo::writing::Json;
+use salvo::Depot;
+
+use crate::models::{
+    DeviceCodeResponse, DevicePoll, DeviceTokenRequest, DEVICE_CODE_LIFETIME,
+    DEVICE_POLL_INTERVAL_SECS,
+};
+use crate::state::State;
+
+const MAX_DEVICE_BODY_SIZE: usize = 1024;
+
+#[handler]
+pub async fn request_device_code(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let verification_uri = format!("{}/", public_origin(req));
+
+    let (device_code, user_code) = State::from_depot(depot)
+        .storage
+        .start_device_authorization();
+    tracing::info!(user_code, "Device authorization started");
+
+    res.render(Json(DeviceCodeResponse {
+        verification_uri_complete: format!("{}?user_code={}", verification_uri, user_code),
+        device_code,
+        user_code,
+        verification_uri,
+        expires_in: DEVICE_CODE_LIFETIME.num_seconds(),
+        interval: DEVICE_POLL_INTERVAL_SECS,
+    }));
+}
+
+#[handler]
+pub async fn poll_device_token(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let request = match req
+        .parse_json_with_max_size::<DeviceTokenRequest>(MAX_DEVICE_BODY_SIZE)
+        .await
+    {
+        Ok(request) => request,
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(format!("invalid payload: {}", e));
+            return;
+        }
+    };
+
+    let poll = State::from_depot(depot)
+        .storage
+        .poll_device_code(&request.device_code);
+
+    // Errors follow the OAuth 2.0 device authorization grant (RFC 8628)
+    match poll {
+        DevicePoll::Approved { token } => {
+            res.render(Json(serde_json::json!({
+                "token": format!("0x{:08X}", token),
+            })));
+        }
+        DevicePoll::Pending => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(
+                serde_json::json!({ "error": "authorization_pending" }),
+            ));
+        }
+        DevicePoll::Expired => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(serde_json::json!({ "error": "expired_token" })));
+        }
+    }
+}
+
+// The origin users reach this server at, honoring the proxy's forwarded protocol
+fn public_origin(req: &Request) -> String {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    let scheme = header("X-Forwarded-Proto").unwrap_or_else(|| req.scheme().to_string());
+    let host = header("X-Forwarded-Host")
+        .or_else(|| header("Host"))
+        .unwrap_or_else(|| "localhost".to_string());
+
+    format!("{}://{}", scheme, host)
+}
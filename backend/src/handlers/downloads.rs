@@ -1,8 +1,11 @@
-use salvo::http::HeaderValue;
+use salvo::http::{HeaderValue, StatusCode};
 use salvo::prelude::{handler, Request, Response};
+use salvo::writing::Json;
 use salvo::Depot;
 
-use crate::state::STORE;
+use crate::models::{Executable, SessionChange, TokenState};
+use crate::state::State;
+use crate::storage::lock;
 
 use super::session::get_session_id;
 
@@ -12,24 +15,115 @@ pub async fn download(req: &mut Request, res: &mut Response, depot: &mut Depot)
         .param::<String>("id")
         .expect("Download ID required to download file");
 
-    let session_id =
-        get_session_id(req, depot).expect("Session ID could not be found via request or depot");
+    let session_id = get_session_id(depot).expect("Session ID could not be found in depot");
 
-    let store = &mut *STORE.lock().await;
+    let store = State::from_depot(depot);
 
     let session = store
-        .sessions
-        .get_mut(&session_id)
+        .storage
+        .session(session_id)
         .expect("Session not found");
     let executable = store
         .executables
+        .load()
         .get(&download_id as &str)
+        .cloned()
         .expect("Executable not found");
 
-    // Create a download for the session
-    let session_download = session.add_download(executable);
-    tracing::info!(session_id, type = download_id, dl_token = session_download.token, "Download created");
-    let data = executable.with_key(session_download.token.to_string().as_bytes());
+    // Create a download for the session, unless it has used up its quota
+    let created = {
+        let mut session = session.lock();
+        session
+            .check_quota(&store.download_quota, executable.data.len() as u64)
+            .map(|_| {
+                session
+                    .add_download(&executable, store.token_lifetime)
+                    .clone()
+            })
+    };
+    let session_download = match created {
+        Ok(session_download) => session_download,
+        Err(exceeded) => {
+            tracing::warn!(session_id = %session_id, quota = ?exceeded.quota, "Download refused, quota exceeded");
+            res.status_code(StatusCode::TOO_MANY_REQUESTS);
+            res.render(Json(exceeded));
+            return;
+        }
+    };
+    store
+        .storage
+        .index_token(session_download.token, session.lock().id);
+    tracing::info!(session_id = %session_id, type = download_id, dl_token = session_download.token, "Download created");
+    serve_executable(
+        res,
+        &executable,
+        session_download.token,
+        &session_download.filename,
+    );
+
+    // Broadcast state to all connected tabs (if any). The change is published regardless,
+    // so tabs that are reconnecting can catch up on it.
+    let mut session = session.lock();
+    let connected = !session.connections.is_empty();
+    let change = SessionChange::DownloadAdded(session_download.token);
+    match session.publish(change, &store.download_quota) {
+        Err(e) if connected => tracing::warn!("Failed to send state update: {}", e),
+        Err(_) => tracing::warn!("Download being made without any WebSocket connections"),
+        Ok(_) => {}
+    }
+}
+
+// Fetches a download reserved over the WebSocket. The URL is the credential, so no session
+// cookie is needed, and it can only be used once.
+#[handler]
+pub async fn download_reserved(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let reservation_id = req
+        .param::<String>("id")
+        .expect("Reservation ID required to download file");
+
+    let store = State::from_depot(depot);
+
+    let Some(reservation) = lock(&store.reservations).take(&reservation_id) else {
+        res.status_code(StatusCode::GONE);
+        return;
+    };
+
+    // The download may have been revoked, or the session purged, since it was reserved
+    let filename = store
+        .storage
+        .session(reservation.session_id)
+        .and_then(|session| {
+            let session = session.lock();
+            match session.token_state(reservation.token) {
+                Some(TokenState::Active) => session
+                    .download(reservation.token)
+                    .map(|reserved| reserved.filename.clone()),
+                _ => None,
+            }
+        });
+    let Some(filename) = filename else {
+        tracing::warn!(session_id = %reservation.session_id, dl_token = reservation.token, "Reserved download is no longer usable");
+        res.status_code(StatusCode::GONE);
+        return;
+    };
+
+    let Some(executable) = store
+        .executables
+        .load()
+        .get(&reservation.executable as &str)
+        .cloned()
+    else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+
+    tracing::info!(session_id = %reservation.session_id, dl_token = reservation.token, "Reserved download fetched");
+    serve_executable(res, &executable, reservation.token, &filename);
+}
+
+// Writes an executable with a token embedded as an attachment
+fn serve_executable(res: &mut Response, executable: &Executable, token: u32, filename: &str) {
+    let data = executable.with_key(token.to_string().as_bytes());
 
     if let Err(e) = res.write_body(data) {
         tracing::error!("Error writing body: {}", e);
@@ -37,22 +131,11 @@ pub async fn download(req: &mut Request, res: &mut Response, depot: &mut Depot)
 
     res.headers.insert(
         "Content-Disposition",
-        HeaderValue::from_str(
-            format!("attachment; filename=\"{}\"", session_download.filename).as_str(),
-        )
-        .expect("Unable to create header"),
+        HeaderValue::from_str(format!("attachment; filename=\"{}\"", filename).as_str())
+            .expect("Unable to create header"),
     );
     res.headers.insert(
         "Content-Type",
         HeaderValue::from_static("application/octet-stream"),
     );
-
-    // Broadcast state to all connected tabs (if any)
-    if !session.connections.is_empty() {
-        if let Err(e) = session.send_state() {
-            tracing::warn!("Failed to send state update: {}", e);
-        }
-    } else {
-        tracing::warn!("Download being made without any WebSocket connections");
-    }
 }
@@ -0,0 +1,221 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+use salvo::http::StatusCode;
+use salvo::prelude::{handler, Request, Response};
+use salvo::sse::{SseEvent, SseKeepAlive};
+use salvo::writing::Json;
+use salvo::Depot;
+
+use crate::models::{
+    CommandError, ErrorCode, IncomingEnvelope, OutgoingMessage, Protocol, SendQueue, ServerInfo,
+    SessionId, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SERVER_CAPABILITIES,
+};
+use crate::state::State;
+
+use super::session::get_session_id;
+use super::websocket::{handle_message, register_connection, send_direct};
+
+/// Streams a session's messages as Server-Sent Events, for clients that can't open a WebSocket.
+///
+/// The stream carries the same messages as `/ws`, each as an unnamed event. It opens with a
+/// `connection` event holding the ID that commands are posted to, at `/events/<id>`. The
+/// protocol is negotiated from the `version`, `min_version` and `last_seq` query parameters,
+/// taking the current version if none is given.
+#[handler]
+pub async fn stream_events(req: &mut Request, res: &mut Response, depot: &Depot) {
+    let session_id = get_session_id(depot).unwrap();
+    let store = State::from_depot(depot);
+
+    let queue = SendQueue::new(store.send_queue, store.metrics.clone());
+    let protocol = match Protocol::negotiate(
+        req.query::<u32>("version").unwrap_or(PROTOCOL_VERSION),
+        req.query::<u32>("min_version"),
+    ) {
+        Ok(protocol) => protocol,
+        Err(reason) => {
+            tracing::warn!(session_id = %session_id, reason, "Rejected event stream client");
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(OutgoingMessage::HelloRejected {
+                reason,
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            }));
+            return;
+        }
+    };
+    let protocol_version = protocol.version;
+
+    send_direct(
+        &queue,
+        &OutgoingMessage::Hello {
+            version: protocol_version,
+            server: ServerInfo::current(),
+            capabilities: SERVER_CAPABILITIES,
+        },
+    );
+    let last_seq = req.query::<u64>("last_seq");
+    let connection_id = register_connection(&store, session_id, &queue, protocol, last_seq);
+
+    tracing::info!(
+        protocol = protocol_version,
+        "Event stream {} established for session {}",
+        connection_id,
+        session_id
+    );
+
+    let heartbeat = store.heartbeat.interval;
+    let guard = StreamGuard {
+        store,
+        session_id,
+        connection_id,
+        queue,
+    };
+    let opening = SseEvent::default()
+        .name("connection")
+        .text(connection_id.to_string());
+    let messages = futures_util::stream::unfold(guard, |guard| async move {
+        while let Some(message) = guard.queue.recv().await {
+            if message.is_close() {
+                break;
+            }
+            // Pings are a WebSocket concern; the stream's keep-alive comments stand in for them
+            if let Ok(text) = message.to_str() {
+                let event = SseEvent::default().text(text);
+                return Some((Ok::<_, Infallible>(event), guard));
+            }
+        }
+        None
+    });
+
+    // Keep-alive comments also reveal a vanished client, since writing them fails
+    SseKeepAlive::new(futures_util::stream::once(async { Ok(opening) }).chain(messages))
+        .max_interval(heartbeat)
+        .stream(res);
+}
+
+/// Carries out a command for an event stream connection, replying in the response body.
+///
+/// The body is the same JSON a WebSocket client would send. Messages a command sends to its
+/// connection, such as a pairing code, arrive on the stream.
+#[handler]
+pub async fn post_command(req: &mut Request, res: &mut Response, depot: &Depot) {
+    let session_id = get_session_id(depot).unwrap();
+    let store = State::from_depot(depot);
+
+    let Some(session) = store.storage.session(session_id) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let connection_id = match req.param::<u64>("connection") {
+        Some(id) if session.lock().connections.contains_key(&id) => id,
+        _ => {
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render("No such event stream for this session");
+            return;
+        }
+    };
+
+    let (request_id, result) = match req.parse_json::<IncomingEnvelope>().await {
+        Ok(IncomingEnvelope {
+            request_id,
+            message,
+        }) => {
+            tracing::debug!(message = ?message, request_id, "Received command");
+            let result = handle_message(&store, session_id, connection_id, &session, message);
+            (request_id, result)
+        }
+        Err(e) => {
+            let error = CommandError::new(ErrorCode::MalformedMessage, e.to_string());
+            (None, Err(error))
+        }
+    };
+
+    match result {
+        Ok(result) => res.render(Json(OutgoingMessage::Response {
+            request_id: request_id.unwrap_or_default(),
+            result,
+        })),
+        Err(error) => {
+            tracing::warn!(session_id = %session_id, connection_id, code = ?error.code, "Command refused: {}", error.message);
+            res.status_code(match error.code {
+                ErrorCode::NotFound => StatusCode::NOT_FOUND,
+                ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            });
+            res.render(Json(error.into_message(request_id)));
+        }
+    }
+}
+
+// Removes an event stream's connection from its session once the client goes away and the
+// stream is dropped
+struct StreamGuard {
+    store: Arc<State>,
+    session_id: SessionId,
+    connection_id: u64,
+    queue: SendQueue,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Some(session) = self.store.storage.session(self.session_id) {
+            session.lock().remove_connection(self.connection_id);
+        }
+        self.queue.close();
+
+        tracing::info!(
+            "Event stream {} closed for session {}",
+            self.connection_id,
+            self.session_id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::TestClient;
+    use salvo::Service;
+
+    use super::*;
+    use crate::routes::router;
+
+    fn connection_count(store: &State) -> usize {
+        store
+            .storage
+            .sessions()
+            .iter()
+            .map(|session| session.lock().connections.len())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn event_streams_are_registered_while_open() {
+        let store = Arc::new(State::new());
+        let service = Service::new(router(store.clone()));
+
+        let response = TestClient::get("http://127.0.0.1/events")
+            .send(&service)
+            .await;
+        assert_eq!(response.status_code, Some(StatusCode::OK));
+        assert_eq!(connection_count(&store), 1);
+
+        // Dropping the response is the client going away
+        drop(response);
+        assert_eq!(connection_count(&store), 0);
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_are_refused() {
+        let store = Arc::new(State::new());
+        let service = Service::new(router(store.clone()));
+
+        let response = TestClient::get("http://127.0.0.1/events?version=0")
+            .send(&service)
+            .await;
+        assert_eq!(response.status_code, Some(StatusCode::BAD_REQUEST));
+        assert_eq!(connection_count(&store), 0);
+    }
+}
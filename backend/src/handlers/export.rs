@@ -0,0 +1,92 @@
+use salvo::http::{HeaderValue, StatusCode};
+use salvo::prelude::{handler, Request, Response};
+use salvo::writing::Json;
+use salvo::Depot;
+
+use crate::models::{SessionExport, SignedExport, MAX_EXPORT_BODY_SIZE};
+use crate::state::State;
+
+use super::session::get_session_id;
+
+#[handler]
+pub async fn export_session(res: &mut Response, depot: &mut Depot) {
+    let store = State::from_depot(depot);
+    let Some(session) = get_session_id(depot).and_then(|id| store.storage.session(id)) else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+
+    let export = SessionExport::new(&session.lock());
+    let filename = format!("session-{}.json", export.session_id);
+    match SignedExport::sign(export, &store.session_keys) {
+        Ok(signed) => {
+            res.headers.insert(
+                "Content-Disposition",
+                HeaderValue::from_str(format!("attachment; filename=\"{}\"", filename).as_str())
+                    .expect("Unable to create header"),
+            );
+            res.render(Json(signed));
+        }
+        Err(e) => {
+            tracing::error!("Error signing session export: {}", e);
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+}
+
+#[handler]
+pub async fn import_session(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let signed = match req
+        .parse_json_with_max_size::<SignedExport>(MAX_EXPORT_BODY_SIZE)
+        .await
+    {
+        Ok(signed) => signed,
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(format!("invalid payload: {}", e));
+            return;
+        }
+    };
+
+    let store = State::from_depot(depot);
+    let export = match signed.verify(&store.session_keys, store.export_max_age) {
+        Ok(export) => export,
+        Err(e) => {
+            tracing::warn!(error = e.to_string(), "Rejected session import");
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(e.to_string());
+            return;
+        }
+    };
+
+    let Some(session_id) = get_session_id(depot) else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    let restored = match store.import_session(session_id, export) {
+        Some(Ok(restored)) => restored,
+        Some(Err(exceeded)) => {
+            tracing::warn!(session_id = %session_id, quota = ?exceeded.quota, "Import refused, quota exceeded");
+            res.status_code(StatusCode::TOO_MANY_REQUESTS);
+            res.render(Json(exceeded));
+            return;
+        }
+        None => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            return;
+        }
+    };
+
+    // Push the restored downloads to any open tabs
+    if let Some(session) = store.storage.session(session_id) {
+        let mut session = session.lock();
+        let connected = !session.connections.is_empty();
+        if let Err(e) = session.send_state(&store.download_quota) {
+            if connected {
+                tracing::warn!("Failed to send state update: {}", e);
+            }
+        }
+    }
+
+    res.render(Json(serde_json::json!({ "restored": restored })));
+}
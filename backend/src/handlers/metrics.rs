@@ -0,0 +1,13 @@
+use salvo::prelude::{handler, Response};
+use salvo::Depot;
+
+use crate::state::State;
+
+#[handler]
+pub async fn get_metrics(res: &mut Response, depot: &Depot) {
+    res.headers_mut().insert(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    );
+    res.render(State::from_depot(depot).metrics.render());
+}
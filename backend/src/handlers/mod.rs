@@ -1,11 +1,21 @@
 mod build_logs;
+mod device;
 mod downloads;
+mod events;
+mod export;
+mod metrics;
 mod notifications;
 mod session;
+mod tokens;
 mod websocket;
 
 pub use build_logs::get_build_logs;
-pub use downloads::download;
+pub use device::{poll_device_token, request_device_code};
+pub use downloads::{download, download_reserved};
+pub use events::{post_command, stream_events};
+pub use export::{export_session, import_session};
+pub use metrics::get_metrics;
 pub use notifications::notify;
-pub use session::{get_session, session_middleware};
+pub use session::{get_session, session_middleware, wait_for_events};
+pub use tokens::exchange_token;
 pub use websocket::connect;
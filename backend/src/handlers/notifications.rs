@@ -1,61 +1,248 @@
 use salvo::http::StatusCode;
 use salvo::prelude::{handler, Request, Response};
+use salvo::Depot;
 
-use crate::models::OutgoingMessage;
-use crate::state::STORE;
+use crate::config::MachineBinding;
+use crate::models::{
+    ClientContext, SessionChange, TokenState, UsageEvent, UsageOutcome, MAX_CONTEXT_BODY_SIZE,
+};
+use crate::state::State;
+
+use super::tokens::{bearer_token, parse_token};
 
 #[handler]
-pub async fn notify(req: &mut Request, res: &mut Response) {
-    let key = req.query::<String>("key");
+pub async fn notify(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    // Binaries either present their embedded key, or an access token obtained from /token/exchange
+    let access_token = bearer_token(req);
+    let key = match &access_token {
+        Some(_) => None,
+        None => match req.query::<String>("key").as_deref().and_then(parse_token) {
+            Some(key) => Some(key),
+            None => {
+                res.status_code(StatusCode::BAD_REQUEST);
+                return;
+            }
+        },
+    };
 
-    if key.is_none() {
-        res.status_code(StatusCode::BAD_REQUEST);
-        return;
-    }
+    // The JSON body is optional; bare `?key=` requests carry no context
+    let context = if req.content_type().is_some() {
+        match req
+            .parse_json_with_max_size::<ClientContext>(MAX_CONTEXT_BODY_SIZE)
+            .await
+        {
+            Ok(context) => {
+                if let Err(e) = context.validate() {
+                    tracing::warn!(error = e.to_string(), "Rejected notify context");
+                    res.status_code(StatusCode::BAD_REQUEST);
+                    res.render(e.to_string());
+                    return;
+                }
+                Some(context)
+            }
+            Err(e) => {
+                tracing::warn!(error = e.to_string(), "Unable to parse notify context");
+                res.status_code(StatusCode::BAD_REQUEST);
+                res.render(format!("invalid payload: {}", e));
+                return;
+            }
+        }
+    } else {
+        None
+    };
 
-    let key = key.unwrap();
+    let store = State::from_depot(depot);
+    let remote_ip = client_ip(req, store.trusted_proxy_hops);
+    let user_agent = req
+        .headers()
+        .get("User-Agent")
+        .and_then(|ua| ua.to_str().ok());
 
-    if !key.starts_with("0x") {
-        res.status_code(StatusCode::BAD_REQUEST);
-        return;
-    }
+    let fingerprint = context
+        .as_ref()
+        .and_then(|context| context.machine_fingerprint.clone());
 
-    // Parse key into u32
-    let key = match u32::from_str_radix(key.trim_start_matches("0x"), 16) {
-        Ok(k) => k,
-        Err(e) => {
-            tracing::error!("Error parsing key: {}", e);
-            res.status_code(StatusCode::BAD_REQUEST);
-            return;
-        }
+    let machine_binding = store.machine_binding;
+
+    let key = match (key, access_token) {
+        (Some(key), _) => key,
+        (None, Some(access_token)) => match store.storage.access_grant(&access_token) {
+            Some(grant) => grant.download_token,
+            None => {
+                tracing::warn!("Invalid or expired access token presented to notify");
+                res.status_code(StatusCode::UNAUTHORIZED);
+                return;
+            }
+        },
+        (None, None) => unreachable!("either a key or an access token is always present"),
     };
 
-    let store = &mut *STORE.lock().await;
+    let target_session = store.session_for_token(key);
 
-    let target_session = store
-        .sessions
-        .iter_mut()
-        .find(|(_, session)| session.downloads.iter().any(|d| d.token == key));
+    let Some((session, token_state)) = target_session else {
+        tracing::warn!("Session not found for key while attempting notify: {}", key);
+        res.status_code(StatusCode::UNAUTHORIZED);
+        return;
+    };
+    let mut session = session.lock();
 
-    match target_session {
-        Some((_, session)) => {
-            let message = OutgoingMessage::TokenAlert { token: key };
+    let machine_mismatch = machine_binding != MachineBinding::Off
+        && token_state == TokenState::Active
+        && !session.bind_machine(key, fingerprint.as_deref());
 
-            if let Err(e) = session.send_message(message) {
+    let outcome = match token_state {
+        TokenState::Revoked => UsageOutcome::Revoked,
+        TokenState::Expired => UsageOutcome::Expired,
+        TokenState::Active if machine_mismatch && machine_binding == MachineBinding::Enforce => {
+            UsageOutcome::MachineMismatch
+        }
+        TokenState::Active => match session.send_alert(key, context.clone(), machine_mismatch) {
+            Ok(outcome) => outcome,
+            Err(e) => {
                 tracing::warn!(
                     error = e.to_string(),
-                    "Session did not have a receiving WebSocket available, notify ignored.",
+                    "Unable to send alert, notify ignored."
                 );
-                res.status_code(StatusCode::NOT_MODIFIED);
-                return;
+                UsageOutcome::Failed
             }
+        },
+    };
+
+    tracing::info!(session_id = %session.id, dl_token = key, outcome = ?outcome, "Token used");
+    session.record_usage(
+        key,
+        UsageEvent::new(outcome, machine_mismatch, remote_ip, user_agent, context),
+    );
+
+    // Push the updated usage history to any open tabs, or keep it for tabs that reconnect
+    let connected = !session.connections.is_empty();
+    if let Err(e) = session.publish(SessionChange::TokenUsed(key), &store.download_quota) {
+        if connected {
+            tracing::warn!("Failed to send state update: {}", e);
+        }
+    }
 
-            res.render("Notification sent");
+    match outcome {
+        UsageOutcome::Delivered => res.render("Notification sent"),
+        UsageOutcome::Queued => {
+            res.status_code(StatusCode::ACCEPTED);
+            res.render("No tab is open, notification queued until one is");
         }
-        None => {
-            tracing::warn!("Session not found for key while attempting notify: {}", key);
-            res.status_code(StatusCode::UNAUTHORIZED);
-            return;
+        UsageOutcome::Failed => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render("Notification could not be sent");
         }
+        UsageOutcome::Revoked => {
+            res.status_code(StatusCode::GONE);
+            res.render("Token has been revoked");
+        }
+        UsageOutcome::Expired => {
+            res.status_code(StatusCode::GONE);
+            res.render("Token has expired");
+        }
+        UsageOutcome::MachineMismatch => {
+            res.status_code(StatusCode::FORBIDDEN);
+            res.render("Token is bound to a different machine");
+        }
+    }
+}
+
+// Acquires the client's IP, as seen by the outermost of `trusted_proxy_hops` proxies
+fn client_ip(req: &Request, trusted_proxy_hops: usize) -> Option<String> {
+    let forwarded = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| forwarded_client(value, trusted_proxy_hops))
+        .map(str::to_string);
+
+    forwarded.or_else(|| {
+        req.remote_addr()
+            .clone()
+            .into_std()
+            .map(|addr| addr.ip().to_string())
+    })
+}
+
+// Picks the client from an X-Forwarded-For list. Each proxy appends the address it received
+// the request from, so only the last `trusted_proxy_hops` entries were written by proxies we
+// trust; anything further left came from the client and can't be believed.
+fn forwarded_client(header: &str, trusted_proxy_hops: usize) -> Option<&str> {
+    let hop = trusted_proxy_hops.checked_sub(1)?;
+    header
+        .rsplit(',')
+        .nth(hop)
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use salvo::test::TestClient;
+    use salvo::Service;
+
+    use super::*;
+    use crate::models::Session;
+    use crate::routes::router;
+
+    #[tokio::test]
+    async fn notify_without_a_tab_is_queued() {
+        let state = Arc::new(State::new());
+        let token = rand::random();
+        let session = state.storage.insert_session(Session::with_download(token));
+        let service = Service::new(router(state));
+
+        let response = TestClient::post(format!("http://127.0.0.1/notify?key=0x{:x}", token))
+            .send(&service)
+            .await;
+
+        assert_eq!(response.status_code, Some(StatusCode::ACCEPTED));
+        assert_eq!(session.lock().pending_alerts.len(), 1);
+    }
+
+    #[test]
+    fn forwarded_client_ignores_entries_set_by_the_client() {
+        let header = "6.6.6.6, 203.0.113.7, 10.0.0.2";
+
+        assert_eq!(forwarded_client(header, 0), None);
+        assert_eq!(forwarded_client(header, 1), Some("10.0.0.2"));
+        assert_eq!(forwarded_client(header, 2), Some("203.0.113.7"));
+        assert_eq!(forwarded_client("203.0.113.7", 2), None);
+    }
+
+    #[tokio::test]
+    async fn notify_is_not_held_up_by_another_sessions_lock() {
+        let state = Arc::new(State::new());
+        let tokens = [0x1001, 0x2002];
+        for token in tokens {
+            state.storage.insert_session(Session::with_download(token));
+        }
+        let service = Service::new(router(state.clone()));
+
+        // A long download or notify holds its session's lock for the duration
+        let busy = state.storage.session_for_token(tokens[0]).unwrap();
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = std::thread::spawn(move || {
+            let _guard = busy.lock();
+            locked_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            TestClient::post(format!("http://127.0.0.1/notify?key=0x{:x}", tokens[1]))
+                .send(&service),
+        )
+        .await
+        .expect("notify waited on another session's lock");
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+
+        assert_eq!(response.status_code, Some(StatusCode::ACCEPTED));
     }
 }
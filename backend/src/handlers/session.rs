@@ -1,66 +1,97 @@
+use std::time::Duration;
+
 use salvo::http::StatusCode;
 use salvo::prelude::{handler, Request, Response};
 use salvo::writing::Json;
 use salvo::Depot;
+use serde::Serialize;
+
+use crate::models::SessionId;
+use crate::signing::Purpose;
+use crate::state::State;
 
-use crate::state::STORE;
+/// How long `/session/wait` blocks when no timeout is given.
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The longest `/session/wait` will block, whatever the client asks for.
+const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Serialize)]
+struct WaitResponse {
+    // The sequence number to pass as `since` on the next call
+    seq: u64,
+    // The same messages a WebSocket client receives, oldest first; empty on timeout
+    events: Vec<serde_json::Value>,
+}
 
 #[handler]
 pub async fn session_middleware(req: &mut Request, res: &mut Response, depot: &mut Depot) {
-    match req.cookie("Session") {
-        Some(cookie) => {
-            // Check if the session exists
-            match cookie.value().parse::<u32>() {
-                Ok(session_id) => {
-                    let mut store = STORE.lock().await;
-                    if !store.sessions.contains_key(&session_id) {
-                        let new_session_id = store.new_session(res).await;
-                        depot.insert("session_id", new_session_id);
+    let store = State::from_depot(depot);
+
+    let session_id = match req.cookie("Session") {
+        Some(cookie) => match store
+            .session_keys
+            .verify(Purpose::SessionCookie, cookie.value())
+        {
+            Some((value, outdated_key)) => match value.parse::<SessionId>() {
+                Ok(session_id) => match store.storage.session(session_id) {
+                    Some(session) => {
+                        session.lock().seen(false);
+
+                        // Re-sign cookies made with a rotated-out key, so it can eventually be dropped
+                        if outdated_key {
+                            tracing::debug!(session_id = %session_id, "Re-signing session cookie");
+                            store.set_session_cookie(res, session_id);
+                        }
+
+                        session_id
+                    }
+                    None => {
+                        let new_session_id = store.new_session(res);
                         tracing::debug!(
-                            existing_session_id = session_id,
-                            new_session_id = new_session_id,
+                            existing_session_id = %session_id,
+                            new_session_id = %new_session_id,
                             "Session provided in cookie, but does not exist"
                         );
-                    } else {
-                        store.sessions.get_mut(&session_id).unwrap().seen(false);
+                        new_session_id
                     }
-                }
+                },
                 Err(parse_error) => {
                     tracing::debug!(
-                        invalid_session_id = cookie.value(),
+                        invalid_session_id = value,
                         error = ?parse_error,
-                        "Session provided in cookie, but is not a valid number"
+                        "Session provided in cookie, but is not a valid ID"
                     );
-                    let mut store = STORE.lock().await;
-                    let id = store.new_session(res).await;
-
-                    depot.insert("session_id", id);
+                    store.new_session(res)
                 }
+            },
+            None => {
+                tracing::warn!("Session cookie has an invalid signature");
+                store.new_session(res)
             }
-        }
+        },
         None => {
             tracing::debug!("Session was not provided in cookie");
-            let mut store = STORE.lock().await;
-            let id = store.new_session(res).await;
-
-            depot.insert("session_id", id);
+            store.new_session(res)
         }
-    }
+    };
+
+    depot.insert("session_id", session_id);
 }
 
 #[handler]
-pub async fn get_session(req: &mut Request, res: &mut Response, depot: &mut Depot) {
-    let store = STORE.lock().await;
-
-    let session_id = get_session_id(req, depot);
+pub async fn get_session(res: &mut Response, depot: &mut Depot) {
+    let session_id = get_session_id(depot);
     if session_id.is_none() {
         res.status_code(StatusCode::BAD_REQUEST);
         return;
     }
 
-    match store.sessions.get(&session_id.unwrap()) {
+    match State::from_depot(depot)
+        .storage
+        .session(session_id.unwrap())
+    {
         Some(session) => {
-            res.render(Json(&session));
+            res.render(Json(&*session.lock()));
         }
         None => {
             res.status_code(StatusCode::BAD_REQUEST);
@@ -68,18 +99,183 @@ pub async fn get_session(req: &mut Request, res: &mut Response, depot: &mut Depo
     }
 }
 
-// Acquires the session id from the request, preferring the depot
-pub fn get_session_id(req: &Request, depot: &Depot) -> Option<u32> {
-    if depot.contains_key("session_id") {
-        return Some(*depot.get::<u32>("session_id").unwrap());
+/// Long-polls for session events, for clients that can't hold a WebSocket.
+///
+/// Returns the events after `since` as soon as there are any, or an empty list once
+/// `timeout` (e.g. `30s` or `500ms`) passes. Without `since`, waits for the next event.
+#[handler]
+pub async fn wait_for_events(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(session_id) = get_session_id(depot) else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    let timeout = match req.query::<String>("timeout") {
+        Some(timeout) => match parse_timeout(&timeout) {
+            Some(timeout) => timeout.min(MAX_WAIT_TIMEOUT),
+            None => {
+                res.status_code(StatusCode::BAD_REQUEST);
+                res.render("invalid timeout, expected e.g. '30s' or '500ms'");
+                return;
+            }
+        },
+        None => DEFAULT_WAIT_TIMEOUT,
+    };
+    let mut since = req.query::<u64>("since");
+
+    let store = State::from_depot(depot);
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        // Resolved on every pass, since pairing can move this session's events elsewhere
+        let Some(session) = store.storage.session(session_id) else {
+            res.status_code(StatusCode::BAD_REQUEST);
+            return;
+        };
+
+        let notified = {
+            let session = session.lock();
+            // Registered while the session is still locked, so an event recorded between the
+            // check below and the wait still wakes this request
+            let mut notified = Box::pin(session.changed().notified_owned());
+            notified.as_mut().enable();
+
+            let since = *since.get_or_insert(session.seq);
+            let events = match session.events_since(since, &store.download_quota) {
+                Ok(events) => events,
+                Err(e) => {
+                    tracing::error!(session_id = %session_id, "Failed to collect events: {}", e);
+                    res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                    return;
+                }
+            };
+            if !events.is_empty() || tokio::time::Instant::now() >= deadline {
+                res.render(Json(WaitResponse {
+                    seq: session.seq,
+                    events,
+                }));
+                return;
+            }
+            notified
+        };
+
+        let _ = tokio::time::timeout_at(deadline, notified).await;
     }
+}
 
-    // Otherwise, just use whatever the Cookie might have
-    match req.cookie("Session") {
-        Some(cookie) => cookie.value().parse::<u32>().ok(),
-        None => {
-            tracing::warn!("Session was not provided in cookie or depot");
+// Parses a timeout such as `30s`, `500ms` or a bare number of seconds
+fn parse_timeout(value: &str) -> Option<Duration> {
+    if let Some(millis) = value.strip_suffix("ms") {
+        return millis.parse().ok().map(Duration::from_millis);
+    }
+    let secs = value.strip_suffix('s').unwrap_or(value);
+    secs.parse().ok().map(Duration::from_secs)
+}
+
+// Acquires the session id verified by `session_middleware` from the depot
+pub fn get_session_id(depot: &Depot) -> Option<SessionId> {
+    match depot.get::<SessionId>("session_id") {
+        Ok(session_id) => Some(*session_id),
+        Err(_) => {
+            tracing::warn!("Session was not provided in depot");
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use salvo::test::{ResponseExt, TestClient};
+    use salvo::Service;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::models::Session;
+    use crate::routes::router;
+
+    // Opens a session, returning the cookie header that identifies it
+    async fn open_session(service: &Service) -> String {
+        let response = TestClient::get("http://127.0.0.1/session")
+            .send(service)
+            .await;
+        let cookie = response.cookie("Session").expect("session cookie");
+        format!("Session={}", cookie.value())
+    }
+
+    async fn wait(service: &Service, cookie: &str, query: &str) -> (StatusCode, Value) {
+        let mut response = TestClient::get(format!("http://127.0.0.1/session/wait?{}", query))
+            .add_header("cookie", cookie, true)
+            .send(service)
+            .await;
+        let status = response.status_code.unwrap();
+        let body = response.take_json::<Value>().await.unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn waits_end_empty_on_timeout() {
+        let store = Arc::new(State::new());
+        let service = Service::new(router(store));
+        let cookie = open_session(&service).await;
+
+        let started = Instant::now();
+        let (status, body) = wait(&service, &cookie, "timeout=100ms").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["events"], Value::Array(Vec::new()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn waits_return_as_soon_as_an_event_is_recorded() {
+        let store = Arc::new(State::new());
+        let service = Service::new(router(store.clone()));
+        let cookie = open_session(&service).await;
+        let session = store.storage.sessions().pop().unwrap();
+
+        let started = Instant::now();
+        let ((status, body), _) = tokio::join!(wait(&service, &cookie, "timeout=30s"), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            // No tab is open, so only the waiting request hears about it
+            let _ = session.lock().send_state(&store.download_quota);
+        });
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["events"].as_array().unwrap().len(), 1);
+        assert_eq!(body["events"][0]["type"], "state");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn waits_follow_a_session_into_the_one_it_is_paired_with() {
+        let store = Arc::new(State::new());
+        let service = Service::new(router(store.clone()));
+        let cookie = open_session(&service).await;
+        let from = store.storage.sessions().pop().unwrap().lock().id;
+        let into = SessionId::random();
+        store.storage.insert_session(Session::new(into));
+
+        let started = Instant::now();
+        let ((status, body), _) = tokio::join!(wait(&service, &cookie, "timeout=10s"), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let paired = store.pair_sessions(from, into).unwrap();
+            let _ = paired.lock().send_state(&store.download_quota);
+        });
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["events"][0]["type"], "state");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn invalid_timeouts_are_refused() {
+        let store = Arc::new(State::new());
+        let service = Service::new(router(store));
+        let cookie = open_session(&service).await;
+
+        let (status, _) = wait(&service, &cookie, "timeout=soon").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,91 @@
+use salvo::http::StatusCode;
+use salvo::prelude::{handler, Request, Response};
+use salvo::writing::Json;
+use salvo::Depot;
+
+use crate::models::{ExchangeRequest, TokenState};
+use crate::state::State;
+
+const MAX_EXCHANGE_BODY_SIZE: usize = 1024;
+
+#[handler]
+pub async fn exchange_token(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let request = match req
+        .parse_json_with_max_size::<ExchangeRequest>(MAX_EXCHANGE_BODY_SIZE)
+        .await
+    {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::debug!(
+                error = e.to_string(),
+                "Unable to parse token exchange request"
+            );
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(format!("invalid payload: {}", e));
+            return;
+        }
+    };
+
+    let store = State::from_depot(depot);
+
+    let grant = match request {
+        ExchangeRequest::Preauth { token } => {
+            let Some(key) = parse_token(&token) else {
+                res.status_code(StatusCode::BAD_REQUEST);
+                return;
+            };
+
+            match store.session_for_token(key) {
+                Some((session, TokenState::Active)) => (session.lock().id, key),
+                _ => {
+                    tracing::warn!("Token exchange attempted with unusable key: {}", key);
+                    res.status_code(StatusCode::UNAUTHORIZED);
+                    return;
+                }
+            }
+        }
+        ExchangeRequest::RefreshToken { refresh_token } => {
+            let Some(grant) = store.storage.take_refresh_grant(&refresh_token) else {
+                res.status_code(StatusCode::UNAUTHORIZED);
+                return;
+            };
+
+            // The underlying download may have been revoked or expired since
+            match store.session_for_token(grant.download_token) {
+                Some((_, TokenState::Active)) => (grant.session_id, grant.download_token),
+                _ => {
+                    res.status_code(StatusCode::UNAUTHORIZED);
+                    return;
+                }
+            }
+        }
+    };
+
+    let (session_id, download_token) = grant;
+    tracing::info!(session_id = %session_id, dl_token = download_token, "Credentials issued");
+    res.render(Json(
+        store.storage.issue_credentials(session_id, download_token),
+    ));
+}
+
+// Parses a `0x`-prefixed hexadecimal download token
+pub(super) fn parse_token(key: &str) -> Option<u32> {
+    let hex = key.strip_prefix("0x")?;
+
+    match u32::from_str_radix(hex, 16) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            tracing::error!("Error parsing key: {}", e);
+            None
+        }
+    }
+}
+
+// Extracts the credential from an `Authorization: Bearer` header
+pub(super) fn bearer_token(req: &Request) -> Option<String> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
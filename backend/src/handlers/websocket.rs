@@ -1,13 +1,19 @@
-use futures_util::{FutureExt, StreamExt};
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
 use salvo::http::StatusError;
 use salvo::prelude::{handler, Request, Response, WebSocketUpgrade};
-use salvo::websocket::WebSocket;
+use salvo::websocket::{Message, WebSocket};
 use salvo::Depot;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use crate::models::{IncomingMessage, OutgoingMessage};
-use crate::state::STORE;
+use crate::models::{
+    normalize_filename, normalize_label, normalize_notes, normalize_tags, CommandError,
+    CommandResult, ErrorCode, IncomingEnvelope, IncomingMessage, OutgoingMessage, Protocol,
+    SendQueue, ServerInfo, SessionChange, SessionId, HELLO_TIMEOUT, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, SERVER_CAPABILITIES, STATE_DIFFS_VERSION,
+};
+use crate::state::State;
+use crate::storage::{lock, SessionHandle};
 
 use super::session::get_session_id;
 
@@ -17,83 +23,143 @@ pub async fn connect(
     res: &mut Response,
     depot: &Depot,
 ) -> Result<(), StatusError> {
-    let session_id = get_session_id(req, depot).unwrap();
+    let session_id = get_session_id(depot).unwrap();
+    let store = State::from_depot(depot);
+    // Clients that speak the handshake say so up front, so no one else waits for a hello
+    let expects_hello = req.queries().contains_key("hello");
     WebSocketUpgrade::new()
         .upgrade(req, res, move |ws| async move {
-            handle_socket(session_id, ws).await;
+            handle_socket(store, session_id, ws, expects_hello).await;
         })
         .await
 }
 
-async fn handle_socket(session_id: u32, websocket: WebSocket) {
+async fn handle_socket(
+    store: Arc<State>,
+    session_id: SessionId,
+    websocket: WebSocket,
+    expects_hello: bool,
+) {
     // Split the socket into a sender and receive of messages.
-    let (socket_tx, mut socket_rx) = websocket.split();
-
-    // Use an unbounded channel to handle buffering and flushing of messages to the websocket...
-    let (tx_channel, tx_channel_rx) = mpsc::unbounded_channel();
-    let transmit = UnboundedReceiverStream::new(tx_channel_rx);
-    let fut_handle_tx_buffer = transmit
-        .then(|message| async {
-            match message {
-                Ok(ref message) => {
-                    tracing::debug!(message = ?message, "Outgoing Message");
-                }
-                Err(ref e) => {
-                    tracing::error!(error = ?e, "Outgoing Message Error");
-                }
-            }
-            message
-        })
-        .forward(socket_tx)
-        .map(|result| {
-            tracing::debug!("WebSocket send result: {:?}", result);
-            if let Err(e) = result {
+    let (mut socket_tx, mut socket_rx) = websocket.split();
+
+    // Messages are buffered in a bounded queue and written by a separate task, so a slow
+    // client can't hold up broadcasts, nor make the server buffer without limit
+    let queue = SendQueue::new(store.send_queue, store.metrics.clone());
+    let writer_queue = queue.clone();
+    let mut writer = tokio::task::spawn(async move {
+        while let Some(message) = writer_queue.recv().await {
+            tracing::debug!(message = ?message, "Outgoing Message");
+            if let Err(e) = socket_tx.send(message).await {
                 tracing::error!(error = ?e, "websocket send error");
+                break;
             }
-        });
-    tokio::task::spawn(fut_handle_tx_buffer);
-
-    // Register connection and get its unique ID
-    let connection_id: u64;
-    {
-        let store = &mut *STORE.lock().await;
-
-        // Create the executable message first, borrow issues
-        let executable_message = OutgoingMessage::Executables {
-            executables: store.executable_json(),
-            build_log: if store.build_logs.is_some() {
-                Some("/build-logs".to_string())
-            } else {
-                None
-            },
-        };
-
-        let session = store
-            .sessions
-            .get_mut(&session_id)
-            .expect("Unable to get session");
-
-        // Register this connection (multi-tab support)
-        connection_id = session.add_connection(tx_channel);
+        }
+        // Refuse further messages, so the session notices the connection is gone
+        writer_queue.close();
+        let _ = socket_tx.close().await;
+    });
 
-        // Send initial state only to this new connection; the other tabs already
-        // hold current state and would only get a redundant update from a broadcast.
-        if let Err(e) = session.send_state_to(connection_id) {
-            tracing::warn!("Failed to send initial state: {}", e);
+    // Negotiate the protocol before sending anything else. Clients predating the handshake
+    // never announce a hello, so they are served the original protocol without waiting.
+    let first = if expects_hello {
+        tokio::time::timeout(HELLO_TIMEOUT, socket_rx.next())
+            .await
+            .ok()
+    } else {
+        None
+    };
+    let (protocol, last_seq, pending) = match first {
+        Some(Some(Ok(msg))) => match parse_hello(&msg) {
+            Some(Ok((protocol, last_seq))) => {
+                let hello = OutgoingMessage::Hello {
+                    version: protocol.version,
+                    server: ServerInfo::current(),
+                    capabilities: SERVER_CAPABILITIES,
+                };
+                send_direct(&queue, &hello);
+                (protocol, last_seq, None)
+            }
+            Some(Err(reason)) => {
+                tracing::warn!(session_id = %session_id, reason, "Rejected WebSocket client");
+                let rejection = OutgoingMessage::HelloRejected {
+                    reason,
+                    min_version: MIN_PROTOCOL_VERSION,
+                    max_version: PROTOCOL_VERSION,
+                };
+                send_direct(&queue, &rejection);
+                let _ = queue.send(Message::close());
+                queue.close();
+                return;
+            }
+            // Not a hello; handle it normally once the connection is registered
+            None => (Protocol::legacy(), None, Some(Ok(msg))),
+        },
+        Some(Some(Err(error))) => {
+            tracing::error!(
+                "WebSocket Error session_id={} error=({})",
+                session_id,
+                error
+            );
+            queue.close();
+            return;
         }
-        if let Err(e) = session.send_message_to(connection_id, &executable_message) {
-            tracing::warn!("Failed to send executables: {}", e);
+        Some(None) => {
+            queue.close();
+            return;
         }
-    }
+        None => (Protocol::legacy(), None, None),
+    };
+    let protocol_version = protocol.version;
+    let mut socket_rx = futures_util::stream::iter(pending).chain(socket_rx);
+
+    let connection_id = register_connection(&store, session_id, &queue, protocol, last_seq);
 
     tracing::info!(
+        protocol = protocol_version,
         "WebSocket connection {} established for session {}",
         connection_id,
         session_id
     );
 
+    // Ping the client regularly, dropping the connection if a pong doesn't arrive in time.
+    // Half-open connections would otherwise linger until a broadcast happened to fail.
+    let heartbeat = store.heartbeat;
+    let mut ping_timer = tokio::time::interval(heartbeat.interval);
+    ping_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ping_timer.tick().await;
+    let pong_deadline = tokio::time::sleep(heartbeat.timeout);
+    tokio::pin!(pong_deadline);
+    let mut awaiting_pong = false;
+
     // Handle incoming messages
-    while let Some(result) = socket_rx.next().await {
+    loop {
+        let result = tokio::select! {
+            result = socket_rx.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = &mut writer => break,
+            _ = ping_timer.tick(), if !awaiting_pong => {
+                if queue.send(Message::ping(Vec::new())).is_err() {
+                    break;
+                }
+                awaiting_pong = true;
+                pong_deadline
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + heartbeat.timeout);
+                continue;
+            }
+            _ = &mut pong_deadline, if awaiting_pong => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    connection_id,
+                    "WebSocket missed its pong deadline, dropping connection"
+                );
+                break;
+            }
+        };
+
         let msg = match result {
             Ok(msg) => msg,
             Err(error) => {
@@ -116,43 +182,66 @@ async fn handle_socket(session_id: u32, websocket: WebSocket) {
             break;
         }
 
+        if msg.is_pong() {
+            awaiting_pong = false;
+            if let Some(session) = store.storage.session(session_id) {
+                session.lock().seen(true);
+            }
+            continue;
+        }
+
         if msg.is_text() {
             let text = msg.to_str().unwrap();
 
+            // Resolved per message, since pairing can move this connection to another session
+            let Some(session) = store.storage.session(session_id) else {
+                tracing::warn!(session_id = %session_id, "Session no longer exists");
+                break;
+            };
+
             // Deserialize
-            match serde_json::from_str::<IncomingMessage>(text) {
-                Ok(message) => {
-                    tracing::debug!(message = ?message, "Received message");
-
-                    match message {
-                        IncomingMessage::DeleteDownloadToken { id } => {
-                            let store = &mut *STORE.lock().await;
-                            let session = store
-                                .sessions
-                                .get_mut(&session_id)
-                                .expect("Session not found");
-
-                            if session.delete_download(id) {
-                                // Broadcast to all tabs
-                                let _ = session.send_state();
-                            }
-                        }
-                    }
+            let (request_id, result) = match serde_json::from_str::<IncomingEnvelope>(text) {
+                Ok(IncomingEnvelope {
+                    request_id,
+                    message,
+                }) => {
+                    tracing::debug!(message = ?message, request_id, "Received message");
+                    let result =
+                        handle_message(&store, session_id, connection_id, &session, message);
+                    (request_id, result)
                 }
                 Err(e) => {
-                    tracing::error!("Error deserializing message: {} {}", text, e);
+                    tracing::debug!("Error deserializing message: {} {}", text, e);
+                    let error = CommandError::new(ErrorCode::MalformedMessage, e.to_string());
+                    (salvage_request_id(text), Err(error))
+                }
+            };
+
+            // Refusals are reported even for commands sent without a request ID
+            let reply = match result {
+                Ok(result) => {
+                    request_id.map(|request_id| OutgoingMessage::Response { request_id, result })
+                }
+                Err(error) => {
+                    tracing::warn!(session_id = %session_id, connection_id, code = ?error.code, "Command refused: {}", error.message);
+                    Some(error.into_message(request_id))
+                }
+            };
+            if let Some(reply) = reply {
+                if let Some(session) = store.storage.session(session_id) {
+                    if let Err(e) = session.lock().send_message_to(connection_id, &reply) {
+                        tracing::warn!("Failed to send reply: {}", e);
+                    }
                 }
             }
         }
     }
 
     // Clean up: remove this connection when the WebSocket closes
-    {
-        let store = &mut *STORE.lock().await;
-        if let Some(session) = store.sessions.get_mut(&session_id) {
-            session.remove_connection(connection_id);
-        }
+    if let Some(session) = store.storage.session(session_id) {
+        session.lock().remove_connection(connection_id);
     }
+    queue.close();
 
     tracing::info!(
         "WebSocket connection {} closed for session {}",
@@ -160,3 +249,566 @@ async fn handle_socket(session_id: u32, websocket: WebSocket) {
         session_id
     );
 }
+
+// Registers a connection with its session and sends it what it needs to get going, returning
+// the connection's ID
+pub(super) fn register_connection(
+    store: &State,
+    session_id: SessionId,
+    queue: &SendQueue,
+    protocol: Protocol,
+    last_seq: Option<u64>,
+) -> u64 {
+    let executable_message = OutgoingMessage::Executables {
+        executables: store.executable_json(),
+        build_log: if store.build_logs.is_some() {
+            Some("/build-logs".to_string())
+        } else {
+            None
+        },
+    };
+
+    let session = store
+        .storage
+        .session(session_id)
+        .expect("Unable to get session");
+    let mut session = session.lock();
+
+    // Register this connection (multi-tab support)
+    let protocol_version = protocol.version;
+    let connection_id = session.add_connection(queue.clone(), protocol);
+
+    // Send initial state only to this new connection; the other tabs already
+    // hold current state and would only get a redundant update from a broadcast.
+    // A reconnecting client that knows where it left off is sent what it missed instead.
+    // Alerts queued while no tab was open are delivered now too.
+    let last_seq = last_seq.filter(|_| protocol_version >= STATE_DIFFS_VERSION);
+    if let Err(e) = session.catch_up(connection_id, last_seq, &store.download_quota) {
+        tracing::warn!("Failed to send initial state: {}", e);
+    }
+    if let Err(e) = session.send_message_to(connection_id, &executable_message) {
+        tracing::warn!("Failed to send executables: {}", e);
+    }
+
+    connection_id
+}
+
+// Carries out a message from a client, returning what it accomplished or why it was refused
+pub(super) fn handle_message(
+    store: &State,
+    session_id: SessionId,
+    connection_id: u64,
+    session: &SessionHandle,
+    message: IncomingMessage,
+) -> Result<CommandResult, CommandError> {
+    match message {
+        IncomingMessage::Hello { .. } => Err(CommandError::new(
+            ErrorCode::UnexpectedHello,
+            "The protocol has already been negotiated",
+        )),
+        IncomingMessage::Unknown => Err(CommandError::new(
+            ErrorCode::UnknownCommand,
+            "Unknown message type",
+        )),
+        IncomingMessage::RevokeToken { id } => {
+            let mut session = session.lock();
+
+            if !session.revoke_download(id) {
+                return Err(download_not_found(id));
+            }
+            tracing::info!(session_id = %session.id, dl_token = id, "Token revoked");
+            let _ = session.publish(SessionChange::DownloadUpdated(id), &store.download_quota);
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::DeleteDownloadToken { id } => {
+            let mut session = session.lock();
+
+            if !session.delete_download(id) {
+                return Err(download_not_found(id));
+            }
+            let _ = session.publish(SessionChange::DownloadRemoved(id), &store.download_quota);
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::ClearDownloads => {
+            let mut session = session.lock();
+
+            let count = session.clear_downloads();
+            tracing::info!(session_id = %session.id, count, "Downloads cleared");
+            if count > 0 {
+                let _ = session.send_state(&store.download_quota);
+            }
+            Ok(CommandResult::Cleared { count })
+        }
+        IncomingMessage::RefreshState => {
+            let session = session.lock();
+
+            session
+                .send_state_to(connection_id, &store.download_quota)
+                .map_err(|e| CommandError::new(ErrorCode::Internal, e.to_string()))?;
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::ReserveDownload { executable } => {
+            let Some(exe) = store.executables.load().get(&executable as &str).cloned() else {
+                return Err(CommandError::new(
+                    ErrorCode::NotFound,
+                    format!("Unknown executable '{}'", executable),
+                ));
+            };
+
+            let mut session = session.lock();
+            if let Err(exceeded) = session.check_quota(&store.download_quota, exe.data.len() as u64)
+            {
+                return Err(CommandError::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("Download quota exceeded: {}", exceeded),
+                ));
+            }
+
+            let token = session.add_download(&exe, store.token_lifetime).token;
+            store.storage.index_token(token, session.id);
+            let (id, expires_at) =
+                lock(&store.reservations).reserve(session.id, token, &executable);
+            tracing::info!(session_id = %session.id, type = executable, dl_token = token, "Download reserved");
+
+            let _ = session.publish(SessionChange::DownloadAdded(token), &store.download_quota);
+            Ok(CommandResult::Reservation {
+                token,
+                url: format!("/download/reserved/{}", id),
+                expires_at,
+            })
+        }
+        IncomingMessage::RenameDownload { id, filename } => {
+            let filename = normalize_filename(filename)?;
+
+            let mut session = session.lock();
+            let download = session
+                .download_mut(id)
+                .ok_or_else(|| download_not_found(id))?;
+            download.filename = filename;
+            let _ = session.publish(SessionChange::DownloadUpdated(id), &store.download_quota);
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::ResetMachineBinding { id } => {
+            let mut session = session.lock();
+
+            if session.download_mut(id).is_none() {
+                return Err(download_not_found(id));
+            }
+            if session.reset_machine_binding(id) {
+                let _ = session.publish(SessionChange::DownloadUpdated(id), &store.download_quota);
+            }
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::SetDownloadLabel { id, label } => {
+            let label = normalize_label(label)?;
+
+            let mut session = session.lock();
+            let download = session
+                .download_mut(id)
+                .ok_or_else(|| download_not_found(id))?;
+            download.label = label;
+            let _ = session.publish(SessionChange::DownloadUpdated(id), &store.download_quota);
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::SetDownloadNotes { id, notes } => {
+            let notes = normalize_notes(notes)?;
+
+            let mut session = session.lock();
+            let download = session
+                .download_mut(id)
+                .ok_or_else(|| download_not_found(id))?;
+            download.notes = notes;
+            let _ = session.publish(SessionChange::DownloadUpdated(id), &store.download_quota);
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::SetDownloadTags { id, tags } => {
+            let tags = normalize_tags(tags)?;
+
+            let mut session = session.lock();
+            let download = session
+                .download_mut(id)
+                .ok_or_else(|| download_not_found(id))?;
+            download.tags = tags;
+            let _ = session.publish(SessionChange::DownloadUpdated(id), &store.download_quota);
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::ApproveDeviceCode { user_code } => {
+            let mut session = session.lock();
+            if let Err(exceeded) = session.check_quota(&store.download_quota, 0) {
+                return Err(CommandError::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("Download quota exceeded: {}", exceeded),
+                ));
+            }
+
+            // Claimed first, so only one of two browsers approving the same code gets a download
+            let token: u32 = rand::random();
+            if !store.storage.approve_device_code(&user_code, token) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidCode,
+                    format!("No device is waiting on code '{}'", user_code),
+                ));
+            }
+            session.add_device_download(&user_code, token, store.token_lifetime);
+            store.storage.index_token(token, session.id);
+            tracing::info!(session_id = %session_id, dl_token = token, "Device code approved");
+
+            let _ = session.publish(SessionChange::DownloadAdded(token), &store.download_quota);
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::RequestPairingCode => {
+            let session = session.lock();
+            let (code, expires_at) = lock(&store.pairing_codes).start(session.id);
+            tracing::info!(session_id = %session.id, "Pairing code issued");
+
+            let message = OutgoingMessage::PairingCode { code, expires_at };
+            session
+                .send_message_to(connection_id, &message)
+                .map_err(|e| CommandError::new(ErrorCode::Internal, e.to_string()))?;
+            Ok(CommandResult::Done)
+        }
+        IncomingMessage::SubmitPairingCode { code } => {
+            let Some(target_id) = lock(&store.pairing_codes).redeem(&code) else {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidCode,
+                    "Unknown or expired pairing code",
+                ));
+            };
+
+            let Some(paired) = store.pair_sessions(session_id, target_id) else {
+                return Err(CommandError::new(
+                    ErrorCode::PairingFailed,
+                    "The pairing code refers to a missing or already paired session",
+                ));
+            };
+            // Broadcast to all tabs of both browsers
+            let _ = paired.lock().send_state(&store.download_quota);
+            Ok(CommandResult::Done)
+        }
+    }
+}
+
+fn download_not_found(token: u32) -> CommandError {
+    CommandError::new(
+        ErrorCode::NotFound,
+        format!("No download with token {}", token),
+    )
+}
+
+// Reads the request ID from a message that could not be deserialized
+fn salvage_request_id(text: &str) -> Option<String> {
+    let value = serde_json::from_str::<serde_json::Value>(text).ok()?;
+    value.get("request_id")?.as_str().map(str::to_string)
+}
+
+// Negotiates a protocol from a client's hello. Returns `None` if the message isn't a hello
+// Also returns the last sequence number the client saw, if it is resuming
+fn parse_hello(msg: &Message) -> Option<Result<(Protocol, Option<u64>), String>> {
+    let text = msg.to_str().ok()?;
+    match serde_json::from_str::<IncomingMessage>(text).ok()? {
+        IncomingMessage::Hello {
+            version,
+            min_version,
+            last_seq,
+        } => Some(Protocol::negotiate(version, min_version).map(|protocol| (protocol, last_seq))),
+        _ => None,
+    }
+}
+
+// Sends a message to a connection that isn't registered with its session yet
+pub(super) fn send_direct(queue: &SendQueue, message: &OutgoingMessage) {
+    match serde_json::to_string(message) {
+        Ok(json) => {
+            let _ = queue.send(Message::text(json));
+        }
+        Err(e) => tracing::error!("Error serializing message: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use salvo::conn::{Acceptor, Listener};
+    use salvo::prelude::{Server, TcpListener};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::models::{DevicePoll, Executable, Heartbeat, Session, TokenState};
+    use crate::routes::router;
+
+    // A session with one download and one open connection, whose queue is returned
+    fn setup() -> (State, SessionId, u64, SendQueue) {
+        let store = State::new();
+        let exe = Executable {
+            data: vec![0; 16],
+            filename: "demo".to_string(),
+            name: "demo".to_string(),
+            extension: String::new(),
+            key_start: 0,
+            key_end: 0,
+        };
+        store.executables.store(Arc::new(HashMap::from([(
+            "Linux".to_string(),
+            Arc::new(exe),
+        )])));
+
+        let mut session = Session::with_download(rand::random());
+        let session_id = session.id;
+        let queue = SendQueue::new(store.send_queue, store.metrics.clone());
+        let connection_id = session.add_connection(queue.clone(), Protocol::legacy());
+        store.storage.insert_session(session);
+
+        (store, session_id, connection_id, queue)
+    }
+
+    // Carries out a command as the socket loop does, returning the response it would send
+    fn command(store: &State, session_id: SessionId, connection_id: u64, message: Value) -> Value {
+        let IncomingEnvelope {
+            request_id,
+            message,
+        } = serde_json::from_value(message).unwrap();
+        let session = store.storage.session(session_id).unwrap();
+        let result = handle_message(store, session_id, connection_id, &session, message).unwrap();
+
+        serde_json::to_value(OutgoingMessage::Response {
+            request_id: request_id.unwrap(),
+            result,
+        })
+        .unwrap()
+    }
+
+    fn first_token(store: &State, session_id: SessionId) -> u32 {
+        store.storage.session(session_id).unwrap().lock().downloads[0].token
+    }
+
+    #[test]
+    fn responses_carry_the_request_id() {
+        let (store, session_id, connection_id, queue) = setup();
+
+        let response = command(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "refresh-state", "request_id": "a1"}),
+        );
+        assert_eq!(response["type"], "response");
+        assert_eq!(response["request_id"], "a1");
+        assert_eq!(response["result"]["kind"], "done");
+        // The refreshed state goes to the requesting connection
+        assert_eq!(queue.depth(), 1);
+    }
+
+    #[test]
+    fn reservations_are_answered_with_their_url() {
+        let (store, session_id, connection_id, _rx) = setup();
+
+        let response = command(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "reserve-download", "executable": "Linux", "request_id": "r"}),
+        );
+        assert_eq!(response["request_id"], "r");
+        assert_eq!(response["result"]["kind"], "reservation");
+        assert!(response["result"]["url"]
+            .as_str()
+            .unwrap()
+            .starts_with("/download/reserved/"));
+    }
+
+    #[test]
+    fn rename_and_revoke_are_answered() {
+        let (store, session_id, connection_id, _rx) = setup();
+        let token = first_token(&store, session_id);
+
+        let renamed = command(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "rename-download", "id": token, "filename": "tool.bin", "request_id": "n"}),
+        );
+        assert_eq!(renamed["request_id"], "n");
+        assert_eq!(
+            store.storage.session(session_id).unwrap().lock().downloads[0].filename,
+            "tool.bin"
+        );
+
+        let revoked = command(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "revoke-token", "id": token, "request_id": "v"}),
+        );
+        assert_eq!(revoked["request_id"], "v");
+        assert_eq!(revoked["result"]["kind"], "done");
+        // Revoked downloads stay listed, unlike deleted ones
+        let session = store.storage.session(session_id).unwrap();
+        assert_eq!(session.lock().downloads.len(), 1);
+        assert_eq!(session.lock().token_state(token), Some(TokenState::Revoked));
+    }
+
+    #[test]
+    fn clearing_reports_how_many_were_revoked() {
+        let (store, session_id, connection_id, _rx) = setup();
+
+        let response = command(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "clear-downloads", "request_id": "c"}),
+        );
+        assert_eq!(response["request_id"], "c");
+        assert_eq!(response["result"]["kind"], "cleared");
+        assert_eq!(response["result"]["count"], 1);
+    }
+
+    fn refusal(store: &State, session_id: SessionId, connection_id: u64, message: Value) -> Value {
+        let IncomingEnvelope {
+            request_id,
+            message,
+        } = serde_json::from_value(message).unwrap();
+        let session = store.storage.session(session_id).unwrap();
+        let error =
+            handle_message(store, session_id, connection_id, &session, message).unwrap_err();
+
+        serde_json::to_value(error.into_message(request_id)).unwrap()
+    }
+
+    #[test]
+    fn malformed_frames_keep_their_request_id() {
+        let text = r#"{"type": "rename-download", "request_id": "m"}"#;
+
+        assert!(serde_json::from_str::<IncomingEnvelope>(text).is_err());
+        assert_eq!(salvage_request_id(text).as_deref(), Some("m"));
+        assert_eq!(salvage_request_id("not json"), None);
+
+        let error = CommandError::new(ErrorCode::MalformedMessage, "missing field");
+        let message = serde_json::to_value(error.into_message(salvage_request_id(text))).unwrap();
+        assert_eq!(message["type"], "error");
+        assert_eq!(message["code"], "malformed_message");
+        assert_eq!(message["request_id"], "m");
+    }
+
+    #[test]
+    fn unknown_and_out_of_place_frames_are_refused() {
+        let (store, session_id, connection_id, _rx) = setup();
+
+        let unknown = refusal(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "launch-rockets", "request_id": "u"}),
+        );
+        assert_eq!(unknown["code"], "unknown_command");
+        assert_eq!(unknown["request_id"], "u");
+
+        let hello = refusal(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "hello", "version": 2}),
+        );
+        assert_eq!(hello["code"], "unexpected_hello");
+        assert_eq!(hello["request_id"], Value::Null);
+    }
+
+    #[test]
+    fn commands_on_missing_downloads_are_not_found() {
+        let (store, session_id, connection_id, _rx) = setup();
+
+        let error = refusal(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "revoke-token", "id": 1, "request_id": "x"}),
+        );
+        assert_eq!(error["code"], "not_found");
+    }
+
+    #[test]
+    fn approvals_over_quota_leave_the_code_unclaimed() {
+        let (mut store, session_id, connection_id, _queue) = setup();
+        store.download_quota.max_live = Some(1);
+        let (device_code, user_code) = store.storage.start_device_authorization();
+
+        let error = refusal(
+            &store,
+            session_id,
+            connection_id,
+            json!({"type": "approve-device-code", "user_code": user_code, "request_id": "q"}),
+        );
+        assert_eq!(error["code"], "quota_exceeded");
+        assert_eq!(error["request_id"], "q");
+        assert!(matches!(
+            store.storage.poll_device_code(&device_code),
+            DevicePoll::Pending
+        ));
+    }
+
+    // Opens a WebSocket to a server and says hello, without ever answering its pings
+    async fn open_silent_socket(addr: std::net::SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /ws?hello HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut headers = Vec::new();
+        while !headers.ends_with(b"\r\n\r\n") {
+            headers.push(stream.read_u8().await.unwrap());
+        }
+        assert!(headers.starts_with(b"HTTP/1.1 101"));
+
+        // A masked text frame; an all-zero mask leaves the payload as is
+        let hello = br#"{"type":"hello","version":2}"#;
+        let mut frame = vec![0x81, 0x80 | hello.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(hello);
+        stream.write_all(&frame).await.unwrap();
+        stream
+    }
+
+    fn connection_count(store: &State) -> usize {
+        store
+            .storage
+            .sessions()
+            .iter()
+            .map(|session| session.lock().connections.len())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn connections_that_miss_the_pong_deadline_are_dropped() {
+        let mut store = State::new();
+        store.heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(50),
+        };
+        let store = Arc::new(store);
+
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor.holdings()[0]
+            .local_addr
+            .clone()
+            .into_std()
+            .unwrap();
+        tokio::spawn(Server::new(acceptor).serve(router(store.clone())));
+
+        let _socket = open_silent_socket(addr).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while connection_count(&store) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            while connection_count(&store) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the silent connection was never dropped");
+    }
+}
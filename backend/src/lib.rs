@@ -1,6 +1,11 @@
 pub mod config;
 pub mod errors;
 pub mod handlers;
+pub mod metrics;
 pub mod models;
+pub mod purge;
 pub mod railway;
+pub mod routes;
+pub mod signing;
 pub mod state;
+pub mod storage;
@@ -1,14 +1,15 @@
 use dynamic_preauth::config::Config;
-use dynamic_preauth::handlers::{
-    connect, download, get_build_logs, get_session, notify, session_middleware,
-};
-use dynamic_preauth::railway;
-use dynamic_preauth::state::STORE;
-
-use salvo::cors::Cors;
-use salvo::http::Method;
+use dynamic_preauth::signing::SigningKeys;
+use dynamic_preauth::state::State;
+use dynamic_preauth::storage::{self, FileStorage};
+use dynamic_preauth::{purge, railway, routes};
+
+use std::sync::Arc;
+
+use salvo::cors::{AllowOrigin, Cors};
+use salvo::http::{header, Method};
 use salvo::logging::Logger;
-use salvo::prelude::{CatchPanic, Listener, Router, Server, Service, StaticDir, TcpListener};
+use salvo::prelude::{Listener, Server, Service, TcpListener};
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
@@ -32,7 +33,20 @@ async fn main() {
         .init();
 
     // Add the build log & executables to the store
-    let mut store = STORE.lock().await;
+    let mut store = State::new();
+    store.token_lifetime = config.token_lifetime();
+    store.machine_binding = config.machine_binding;
+    store.download_quota = config.download_quota();
+    store.heartbeat = config.heartbeat();
+    store.send_queue = config.send_queue();
+    store.export_max_age = config.export_max_age();
+    store.trusted_proxy_hops = config.trusted_proxy_hops();
+
+    let session_secrets = config.session_secrets();
+    if session_secrets.is_empty() {
+        tracing::warn!("SESSION_SECRETS not set, session cookies will not survive a restart");
+    }
+    store.session_keys = SigningKeys::new(session_secrets);
 
     // Check if we are deployed on Railway
     if config.railway.is_railway() {
@@ -81,38 +95,53 @@ async fn main() {
         }
     }
 
-    drop(store); // critical: Drop the lock to avoid deadlock, otherwise the server will hang
+    // Choose where sessions & tokens live, restoring them from the last run if persisted
+    if let Some(state_path) = &config.state_path {
+        match FileStorage::open(state_path) {
+            Ok(storage) => store.storage = Box::new(storage),
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let store = Arc::new(store);
 
     let origin = config.railway.cors_origin();
+    // Imports and event stream commands are credentialed POSTs, which browsers only allow when
+    // the exact origin is echoed back; in development, any origin is mirrored for that reason
     let cors = Cors::new()
-        .allow_origin(&origin)
-        .allow_methods(vec![Method::GET])
-        .into_handler();
+        .allow_methods(vec![Method::GET, Method::POST])
+        .allow_headers(vec![header::CONTENT_TYPE]);
+    let cors = if origin != "*" {
+        cors.allow_origin(&origin).allow_credentials(true)
+    } else if cfg!(debug_assertions) {
+        cors.allow_origin(AllowOrigin::mirror_request())
+            .allow_credentials(true)
+    } else {
+        cors.allow_origin(&origin)
+    }
+    .into_handler();
     tracing::debug!("CORS Allowed Origin: {}", &origin);
 
-    let static_dir = StaticDir::new(["./public"]).defaults("index.html");
-
     // TODO: Improved Token Generation
     // TODO: Advanced HMAC Verification
-    // TODO: Session Purging
-
-    let router = Router::new()
-        .hoop(CatchPanic::new())
-        // /notify does not need a session, nor should it have one
-        .push(Router::with_path("notify").post(notify))
-        // /build-logs does not need a session
-        .push(Router::with_path("build-logs").get(get_build_logs))
-        .push(
-            Router::new()
-                .hoop(session_middleware)
-                .push(Router::with_path("download/<id>").get(download))
-                .push(Router::with_path("session").get(get_session))
-                // websocket /ws
-                .push(Router::with_path("ws").goal(connect))
-                // static files
-                .push(Router::with_path("<**path>").get(static_dir)),
-        );
 
+    let purge_policy = config.purge_policy();
+    tracing::info!(
+        interval = ?purge_policy.interval,
+        idle_timeout = %purge_policy.idle_timeout,
+        max_lifetime = %purge_policy.max_lifetime,
+        "Session purging enabled"
+    );
+    purge::spawn_session_purger(store.clone(), purge_policy);
+
+    if config.state_path.is_some() {
+        storage::spawn_flusher(store.clone(), config.state_snapshot_interval());
+    }
+
+    let router = routes::router(store.clone());
     let service = Service::new(router).hoop(cors).hoop(Logger::new());
 
     let bind_addr = config.bind_addr();
@@ -125,5 +154,43 @@ async fn main() {
     }
 
     let acceptor = TcpListener::new(&bind_addr).bind().await;
-    Server::new(acceptor).serve(service).await;
+    let server = Server::new(acceptor);
+
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("Shutdown signal received, stopping server");
+        handle.stop_graceful(std::time::Duration::from_secs(10));
+    });
+
+    server.serve(service).await;
+
+    // Take a final snapshot so nothing since the last interval is lost
+    if let Err(e) = storage::flush(store).await {
+        tracing::error!("{}", e);
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
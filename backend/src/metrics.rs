@@ -0,0 +1,71 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A server's counters and gauges, rendered in the Prometheus text format by `/metrics`.
+///
+/// Each `State` owns its own, so servers sharing a process don't report each other's numbers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub purge_sweeps_total: AtomicU64,
+    pub sessions_purged_total: AtomicU64,
+    pub sessions_active: AtomicU64,
+    pub ws_send_queue_depth: AtomicU64,
+    pub ws_send_queue_dropped_total: AtomicU64,
+    pub ws_slow_disconnects_total: AtomicU64,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        for (name, kind, help, value) in [
+            (
+                "purge_sweeps_total",
+                "counter",
+                "Number of session purge sweeps run",
+                &self.purge_sweeps_total,
+            ),
+            (
+                "sessions_purged_total",
+                "counter",
+                "Number of sessions evicted by purge sweeps",
+                &self.sessions_purged_total,
+            ),
+            (
+                "sessions_active",
+                "gauge",
+                "Number of sessions held after the last purge sweep",
+                &self.sessions_active,
+            ),
+            (
+                "ws_send_queue_depth",
+                "gauge",
+                "Number of messages waiting to be written across all WebSocket connections",
+                &self.ws_send_queue_depth,
+            ),
+            (
+                "ws_send_queue_dropped_total",
+                "counter",
+                "Number of WebSocket messages dropped or coalesced because a send queue was full",
+                &self.ws_send_queue_dropped_total,
+            ),
+            (
+                "ws_slow_disconnects_total",
+                "counter",
+                "Number of WebSocket connections closed for falling too far behind",
+                &self.ws_slow_disconnects_total,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP dynamic_preauth_{} {}", name, help);
+            let _ = writeln!(output, "# TYPE dynamic_preauth_{} {}", name, kind);
+            let _ = writeln!(
+                output,
+                "dynamic_preauth_{} {}",
+                name,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        output
+    }
+}
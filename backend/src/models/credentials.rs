@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::session::SessionId;
+
+/// How long an access token issued by `/token/exchange` remains valid.
+pub const ACCESS_TOKEN_LIFETIME: chrono::Duration = chrono::Duration::minutes(15);
+/// How long a refresh token remains valid. Each refresh rotates it.
+pub const REFRESH_TOKEN_LIFETIME: chrono::Duration = chrono::Duration::days(30);
+/// Maximum access tokens, and refresh tokens, outstanding for one download token. Issuing
+/// more revokes the oldest, so repeated exchanges can't grow the store without limit.
+pub const MAX_GRANTS_PER_TOKEN: usize = 4;
+
+/// A request to `/token/exchange`, discriminated by `grant_type`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+pub enum ExchangeRequest {
+    // Trade the embedded preauth token for a credential pair
+    Preauth { token: String },
+    // Trade a refresh token for a new credential pair
+    RefreshToken { refresh_token: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub refresh_token: String,
+    pub refresh_expires_in: i64,
+}
+
+/// The download token a credential was issued for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub session_id: SessionId,
+    pub download_token: u32,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Grant {
+    fn is_expired(&self) -> bool {
+        self.expires_at <= chrono::Utc::now()
+    }
+}
+
+/// Short-lived access tokens and refresh tokens issued in exchange for preauth tokens.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    access: HashMap<String, Grant>,
+    refresh: HashMap<String, Grant>,
+}
+
+impl Credentials {
+    // Issue a new access & refresh token pair for a download token
+    pub fn issue(&mut self, session_id: SessionId, download_token: u32) -> TokenResponse {
+        self.purge_expired();
+
+        let now = chrono::Utc::now();
+        let access_token = random_token();
+        let refresh_token = random_token();
+
+        self.access.insert(
+            access_token.clone(),
+            Grant {
+                session_id,
+                download_token,
+                expires_at: now + ACCESS_TOKEN_LIFETIME,
+            },
+        );
+        self.refresh.insert(
+            refresh_token.clone(),
+            Grant {
+                session_id,
+                download_token,
+                expires_at: now + REFRESH_TOKEN_LIFETIME,
+            },
+        );
+        cap_grants(&mut self.access, download_token);
+        cap_grants(&mut self.refresh, download_token);
+
+        TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: ACCESS_TOKEN_LIFETIME.num_seconds(),
+            refresh_token,
+            refresh_expires_in: REFRESH_TOKEN_LIFETIME.num_seconds(),
+        }
+    }
+
+    // Look up the grant behind an access token, if it exists and has not expired
+    pub fn access_grant(&mut self, access_token: &str) -> Option<Grant> {
+        let grant = self.access.get(access_token)?;
+        if grant.is_expired() {
+            self.access.remove(access_token);
+            return None;
+        }
+        Some(grant.clone())
+    }
+
+    // Consume a refresh token, returning its grant if it was still valid
+    pub fn take_refresh_grant(&mut self, refresh_token: &str) -> Option<Grant> {
+        self.refresh
+            .remove(refresh_token)
+            .filter(|grant| !grant.is_expired())
+    }
+
+    // Drop every credential issued for a session
+    pub fn revoke_session(&mut self, session_id: SessionId) {
+        self.access
+            .retain(|_, grant| grant.session_id != session_id);
+        self.refresh
+            .retain(|_, grant| grant.session_id != session_id);
+    }
+
+    // Move every credential issued for one session over to another
+    pub fn reassign_session(&mut self, from: SessionId, to: SessionId) {
+        for grant in self.access.values_mut().chain(self.refresh.values_mut()) {
+            if grant.session_id == from {
+                grant.session_id = to;
+            }
+        }
+    }
+
+    fn purge_expired(&mut self) {
+        self.access.retain(|_, grant| !grant.is_expired());
+        self.refresh.retain(|_, grant| !grant.is_expired());
+    }
+}
+
+// Drop the oldest grants for a download token beyond `MAX_GRANTS_PER_TOKEN`
+fn cap_grants(grants: &mut HashMap<String, Grant>, download_token: u32) {
+    let mut issued: Vec<(chrono::DateTime<chrono::Utc>, String)> = grants
+        .iter()
+        .filter(|(_, grant)| grant.download_token == download_token)
+        .map(|(token, grant)| (grant.expires_at, token.clone()))
+        .collect();
+    if issued.len() <= MAX_GRANTS_PER_TOKEN {
+        return;
+    }
+
+    // Every grant of a kind has the same lifetime, so the earliest to expire is the oldest
+    issued.sort();
+    for (_, token) in &issued[..issued.len() - MAX_GRANTS_PER_TOKEN] {
+        grants.remove(token);
+    }
+}
+
+fn random_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_exchanges_keep_only_the_newest_grants() {
+        let mut credentials = Credentials::default();
+        let session_id = SessionId::random();
+
+        let first = credentials.issue(session_id, 1);
+        let other = credentials.issue(session_id, 2);
+        let mut latest = Vec::new();
+        for _ in 0..MAX_GRANTS_PER_TOKEN {
+            latest.push(credentials.issue(session_id, 1));
+        }
+
+        assert_eq!(credentials.access.len(), MAX_GRANTS_PER_TOKEN + 1);
+        assert_eq!(credentials.refresh.len(), MAX_GRANTS_PER_TOKEN + 1);
+        assert!(credentials.access_grant(&first.access_token).is_none());
+        assert!(credentials
+            .take_refresh_grant(&first.refresh_token)
+            .is_none());
+        // Grants for other download tokens are untouched
+        assert!(credentials.access_grant(&other.access_token).is_some());
+        for issued in &latest {
+            assert!(credentials.access_grant(&issued.access_token).is_some());
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How long a device code remains valid while waiting for approval.
+pub const DEVICE_CODE_LIFETIME: chrono::Duration = chrono::Duration::minutes(10);
+/// Minimum number of seconds a device should wait between polls.
+pub const DEVICE_POLL_INTERVAL_SECS: u64 = 5;
+
+// Consonants only, so codes are easy to read aloud and never spell words
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+#[derive(Debug, Serialize)]
+pub struct DeviceCodeResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceTokenRequest {
+    pub device_code: String,
+}
+
+/// The result of a device polling for its token.
+#[derive(Debug)]
+pub enum DevicePoll {
+    // The user has not approved the code yet
+    Pending,
+    // The user approved the code; the token is handed out exactly once
+    Approved { token: u32 },
+    // The code is unknown, already redeemed or expired
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DeviceAuthorization {
+    user_code: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    // The download token assigned once a browser session approves the code
+    token: Option<u32>,
+}
+
+/// Pending device authorizations, keyed by device code.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DeviceAuthorizations {
+    pending: HashMap<String, DeviceAuthorization>,
+}
+
+impl DeviceAuthorizations {
+    // Start a new device authorization, returning its device & user codes
+    pub fn start(&mut self) -> (String, String) {
+        self.purge_expired();
+
+        let device_code = hex::encode(rand::random::<[u8; 32]>());
+        let user_code = generate_user_code();
+
+        self.pending.insert(
+            device_code.clone(),
+            DeviceAuthorization {
+                user_code: user_code.clone(),
+                expires_at: chrono::Utc::now() + DEVICE_CODE_LIFETIME,
+                token: None,
+            },
+        );
+
+        (device_code, user_code)
+    }
+
+    // Approve the device awaiting approval under a user-entered code with a download token
+    // Returns false if no device is waiting on the code, including one already approved
+    pub fn approve_pending(&mut self, user_code: &str, token: u32) -> bool {
+        let user_code = normalize_user_code(user_code);
+        let now = chrono::Utc::now();
+
+        match self.pending.values_mut().find(|authorization| {
+            authorization.user_code == user_code
+                && authorization.token.is_none()
+                && authorization.expires_at > now
+        }) {
+            Some(authorization) => {
+                authorization.token = Some(token);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Poll a device code, consuming it once its token has been handed out
+    pub fn poll(&mut self, device_code: &str) -> DevicePoll {
+        match self.pending.get(device_code) {
+            Some(authorization) if authorization.expires_at <= chrono::Utc::now() => {
+                self.pending.remove(device_code);
+                DevicePoll::Expired
+            }
+            Some(DeviceAuthorization {
+                token: Some(token), ..
+            }) => {
+                let token = *token;
+                self.pending.remove(device_code);
+                DevicePoll::Approved { token }
+            }
+            Some(_) => DevicePoll::Pending,
+            None => DevicePoll::Expired,
+        }
+    }
+
+    fn purge_expired(&mut self) {
+        let now = chrono::Utc::now();
+        self.pending
+            .retain(|_, authorization| authorization.expires_at > now);
+    }
+}
+
+pub(super) fn generate_user_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code: String = (0..8)
+        .map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+        .collect();
+    code.insert(4, '-');
+    code
+}
+
+// Accept codes typed in lowercase, or without the separating dash
+pub(super) fn normalize_user_code(user_code: &str) -> String {
+    let mut code: String = user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if code.len() == 8 {
+        code.insert(4, '-');
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_is_approved_only_once() {
+        let mut authorizations = DeviceAuthorizations::default();
+        let (device_code, user_code) = authorizations.start();
+
+        assert!(matches!(
+            authorizations.poll(&device_code),
+            DevicePoll::Pending
+        ));
+        assert!(authorizations.approve_pending(&user_code.to_lowercase(), 7));
+        assert!(!authorizations.approve_pending(&user_code, 8));
+        assert!(matches!(
+            authorizations.poll(&device_code),
+            DevicePoll::Approved { token: 7 }
+        ));
+        assert!(matches!(
+            authorizations.poll(&device_code),
+            DevicePoll::Expired
+        ));
+    }
+}
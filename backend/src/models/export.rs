@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::signing::{Purpose, SigningKeys};
+
+use super::session::{Session, SessionDownload, SessionId};
+
+/// Bumped whenever the export layout changes incompatibly.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Maximum size of an export accepted by `/session/import`.
+pub const MAX_EXPORT_BODY_SIZE: usize = 512 * 1024;
+
+/// How old an export may be before `/session/import` refuses it, unless configured otherwise.
+pub const DEFAULT_EXPORT_MAX_AGE: chrono::Duration = chrono::Duration::days(30);
+
+/// A session's downloads and their history, as handed to the user for safekeeping.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionExport {
+    pub version: u32,
+    pub session_id: SessionId,
+    pub exported_at: chrono::DateTime<chrono::Utc>,
+    pub downloads: Vec<SessionDownload>,
+    pub revoked: VecDeque<SessionDownload>,
+}
+
+impl SessionExport {
+    pub fn new(session: &Session) -> Self {
+        Self {
+            version: EXPORT_VERSION,
+            session_id: session.id,
+            exported_at: chrono::Utc::now(),
+            downloads: session.downloads.clone(),
+            revoked: session.revoked.clone(),
+        }
+    }
+}
+
+/// A `SessionExport` with a signature proving this server produced it.
+///
+/// The signature covers the export as serialized by this server, so only the server holding
+/// the signing keys can mint a bundle that imports successfully.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedExport {
+    pub export: SessionExport,
+    pub signature: String,
+}
+
+impl SignedExport {
+    pub fn sign(export: SessionExport, keys: &SigningKeys) -> anyhow::Result<Self> {
+        let signature = keys.signature(Purpose::SessionExport, &serde_json::to_vec(&export)?);
+        Ok(Self { export, signature })
+    }
+
+    /// Returns the export if its signature is valid, its version supported, and it is no older
+    /// than `max_age`. Exports carry live tokens, so a leaked one must not stay usable forever.
+    pub fn verify(
+        self,
+        keys: &SigningKeys,
+        max_age: chrono::Duration,
+    ) -> anyhow::Result<SessionExport> {
+        let data = serde_json::to_vec(&self.export)?;
+        if keys
+            .verify_signature(Purpose::SessionExport, &data, &self.signature)
+            .is_none()
+        {
+            return Err(anyhow::anyhow!("export signature is invalid"));
+        }
+        if self.export.version != EXPORT_VERSION {
+            return Err(anyhow::anyhow!(
+                "export version {} is not supported (expected {})",
+                self.export.version,
+                EXPORT_VERSION
+            ));
+        }
+        if chrono::Utc::now() - self.export.exported_at > max_age {
+            return Err(anyhow::anyhow!(
+                "export from {} is too old to import",
+                self.export.exported_at
+            ));
+        }
+
+        Ok(self.export)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_export(keys: &SigningKeys) -> SignedExport {
+        let session = Session::with_download(7);
+        SignedExport::sign(SessionExport::new(&session), keys).unwrap()
+    }
+
+    #[test]
+    fn signed_export_verifies() {
+        let keys = SigningKeys::new(vec!["secret".to_string()]);
+        let export = signed_export(&keys)
+            .verify(&keys, DEFAULT_EXPORT_MAX_AGE)
+            .unwrap();
+
+        assert_eq!(export.downloads[0].token, 7);
+    }
+
+    #[test]
+    fn tampered_export_is_rejected() {
+        let keys = SigningKeys::new(vec!["secret".to_string()]);
+        let mut signed = signed_export(&keys);
+        signed.export.downloads[0].token = 8;
+
+        assert!(signed.verify(&keys, DEFAULT_EXPORT_MAX_AGE).is_err());
+    }
+
+    #[test]
+    fn export_signed_elsewhere_is_rejected() {
+        let keys = SigningKeys::new(vec!["secret".to_string()]);
+        let other = SigningKeys::new(vec!["other".to_string()]);
+
+        assert!(signed_export(&other)
+            .verify(&keys, DEFAULT_EXPORT_MAX_AGE)
+            .is_err());
+    }
+
+    #[test]
+    fn stale_export_is_rejected() {
+        let keys = SigningKeys::new(vec!["secret".to_string()]);
+        let session = Session::with_download(7);
+        let mut export = SessionExport::new(&session);
+        export.exported_at -= chrono::Duration::days(2);
+        let signed = SignedExport::sign(export, &keys).unwrap();
+
+        assert!(signed.verify(&keys, chrono::Duration::days(1)).is_err());
+    }
+}
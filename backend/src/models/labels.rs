@@ -0,0 +1,132 @@
+use crate::errors::{AppError, Result};
+
+use super::usage::check_field;
+
+const MAX_LABEL_LEN: usize = 64;
+const MAX_NOTES_LEN: usize = 1024;
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LEN: usize = 32;
+const MAX_FILENAME_LEN: usize = 128;
+
+// Trims a user-supplied value, treating an empty one as cleared
+fn trimmed(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Validates a download label, returning it trimmed.
+pub fn normalize_label(label: Option<String>) -> Result<Option<String>> {
+    let label = trimmed(label);
+    check_field("label", &label, MAX_LABEL_LEN)?;
+    Ok(label)
+}
+
+/// Validates a new filename for a download, returning it trimmed.
+pub fn normalize_filename(filename: String) -> Result<String> {
+    let Some(filename) = trimmed(Some(filename)) else {
+        return Err(AppError::InvalidPayload {
+            message: "'filename' must not be empty".to_string(),
+        });
+    };
+    check_field("filename", &Some(filename.clone()), MAX_FILENAME_LEN)?;
+
+    // Served in a Content-Disposition header, so it must not escape the quoted value
+    if filename.contains(['/', '\\', '"']) {
+        return Err(AppError::InvalidPayload {
+            message: "'filename' contains path separators or quotes".to_string(),
+        });
+    }
+
+    Ok(filename)
+}
+
+/// Validates download notes, returning them trimmed. Unlike labels, notes may span lines.
+pub fn normalize_notes(notes: Option<String>) -> Result<Option<String>> {
+    let notes = trimmed(notes);
+    let Some(value) = &notes else {
+        return Ok(None);
+    };
+
+    if value.chars().count() > MAX_NOTES_LEN {
+        return Err(AppError::InvalidPayload {
+            message: format!("'notes' exceeds {} characters", MAX_NOTES_LEN),
+        });
+    }
+
+    if value
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+    {
+        return Err(AppError::InvalidPayload {
+            message: "'notes' contains control characters".to_string(),
+        });
+    }
+
+    Ok(notes)
+}
+
+/// Validates download tags, returning them trimmed with empty and duplicate tags removed.
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>> {
+    let mut normalized: Vec<String> = Vec::new();
+
+    for tag in tags {
+        let tag = trimmed(Some(tag));
+        check_field("tag", &tag, MAX_TAG_LEN)?;
+        if let Some(tag) = tag {
+            if !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+    }
+
+    if normalized.len() > MAX_TAGS {
+        return Err(AppError::InvalidPayload {
+            message: format!("a download can have at most {} tags", MAX_TAGS),
+        });
+    }
+
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn labels_are_trimmed_and_blank_ones_cleared() {
+        assert_eq!(
+            normalize_label(Some("  build box ".to_string())).unwrap(),
+            Some("build box".to_string())
+        );
+        assert_eq!(normalize_label(Some("   ".to_string())).unwrap(), None);
+        assert!(normalize_label(Some("x".repeat(MAX_LABEL_LEN + 1))).is_err());
+        assert!(normalize_label(Some("two\nlines".to_string())).is_err());
+    }
+
+    #[test]
+    fn notes_may_span_lines() {
+        assert_eq!(
+            normalize_notes(Some("first\n\tsecond\n".to_string())).unwrap(),
+            Some("first\n\tsecond".to_string())
+        );
+        assert!(normalize_notes(Some("bell\u{7}".to_string())).is_err());
+        assert!(normalize_notes(Some("x".repeat(MAX_NOTES_LEN + 1))).is_err());
+    }
+
+    #[test]
+    fn tags_are_deduplicated_and_limited() {
+        assert_eq!(
+            normalize_tags(strings(&[" ci ", "ci", "", "prod"])).unwrap(),
+            strings(&["ci", "prod"])
+        );
+
+        let too_many = (0..=MAX_TAGS).map(|i| format!("tag-{}", i)).collect();
+        assert!(normalize_tags(too_many).is_err());
+        assert!(normalize_tags(vec!["x".repeat(MAX_TAG_LEN + 1)]).is_err());
+    }
+}
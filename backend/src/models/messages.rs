@@ -1,29 +1,252 @@
 use serde::{Deserialize, Serialize};
 
+use crate::errors::AppError;
+
 use super::executable::ExecutableJson;
-use super::session::Session;
+use super::protocol::ServerInfo;
+use super::quota::QuotaUsage;
+use super::session::{Session, SessionDownload};
+use super::usage::{ClientContext, UsageEvent};
+
+/// A message from the client, optionally tagged with an ID to correlate its response.
+#[derive(Debug, Deserialize)]
+pub struct IncomingEnvelope {
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: IncomingMessage,
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "kebab-case")]
 pub enum IncomingMessage {
+    // The first message from a client, negotiating the protocol version
+    Hello {
+        version: u32,
+        // The oldest version the client can fall back to
+        min_version: Option<u32>,
+        // The last sequence number a reconnecting client saw, so it can be sent what it missed
+        last_seq: Option<u64>,
+    },
     // A request from the client to delete a download token
-    DeleteDownloadToken { id: u32 },
+    DeleteDownloadToken {
+        id: u32,
+    },
+    // A request from the client to revoke a download token, keeping the download listed
+    RevokeToken {
+        id: u32,
+    },
+    // A request from the client to revoke every download in the session
+    ClearDownloads,
+    // A request from the client for the current session state
+    RefreshState,
+    // A request from the client to create a download, fetched later from a one-time URL
+    ReserveDownload {
+        executable: String,
+    },
+    // A request from the client to change the filename a download is served under
+    RenameDownload {
+        id: u32,
+        filename: String,
+    },
+    // A request from the client to forget the machine a download token is bound to
+    ResetMachineBinding {
+        id: u32,
+    },
+    // A request from the client to approve a device waiting on a user code
+    ApproveDeviceCode {
+        user_code: String,
+    },
+    // A request from the client to name a download; a missing or empty label clears it
+    SetDownloadLabel {
+        id: u32,
+        label: Option<String>,
+    },
+    // A request from the client to attach free-form notes to a download
+    SetDownloadNotes {
+        id: u32,
+        notes: Option<String>,
+    },
+    // A request from the client to replace the tags on a download
+    SetDownloadTags {
+        id: u32,
+        tags: Vec<String>,
+    },
+    // A request from the client for a code another browser can use to join this session
+    RequestPairingCode,
+    // A request from the client to join the session a pairing code was issued for
+    SubmitPairingCode {
+        code: String,
+    },
+    // Any message type this server does not know, so it can be reported back to the client
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "kebab-case")]
-pub enum OutgoingMessage {
+pub enum OutgoingMessage<'a> {
+    // The reply to a client's hello, with the version both sides will speak
+    Hello {
+        version: u32,
+        server: ServerInfo,
+        capabilities: &'static [&'static str],
+    },
+    // The reply to a client's hello when no version is acceptable; the socket is then closed
+    HelloRejected {
+        reason: String,
+        min_version: u32,
+        max_version: u32,
+    },
     // An alert to the client that a session download has been used.
     #[serde(rename = "notify")]
     TokenAlert {
+        seq: u64,
+        token: u32,
+        // Context reported by the binary, if it sent any
+        context: Option<ClientContext>,
+        // True if the token was used from a machine other than the one it is bound to
+        machine_mismatch: bool,
+        // The label and tags the user gave the download, so the alert can name it
+        label: Option<String>,
+        tags: Vec<String>,
+        // When the alert was queued, if it was raised while no tab was open
+        queued_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    // A download was created; the rest of the state is unchanged
+    DownloadAdded {
+        seq: u64,
+        download: SessionDownload,
+        quota: QuotaUsage,
+    },
+    // A download was revoked or deleted
+    DownloadRemoved {
+        seq: u64,
+        token: u32,
+        quota: QuotaUsage,
+    },
+    // A download's details changed; it replaces the client's copy
+    DownloadUpdated {
+        seq: u64,
+        download: SessionDownload,
+    },
+    // A download's token was presented to /notify, successfully or not
+    TokenUsed {
+        seq: u64,
         token: u32,
+        last_used: chrono::DateTime<chrono::Utc>,
+        usage: UsageEvent,
+    },
+    // Sent to a reconnecting client instead of the state, before the events it missed
+    Resumed {
+        // The sequence number the client left off at
+        seq: u64,
+        // How many events follow
+        replayed: usize,
     },
     // A message describing the current session state
     State {
-        session: Session,
+        // Borrowed, since the state is serialized once per broadcast and never kept
+        session: &'a Session,
+        // How much of the session's download quota is used up
+        quota: QuotaUsage,
     },
     Executables {
         build_log: Option<String>,
         executables: Vec<ExecutableJson>,
     },
+    // The outcome of a command sent with a request ID
+    Response {
+        request_id: String,
+        result: CommandResult,
+    },
+    // A message from the client that could not be parsed or carried out
+    Error {
+        // The ID the client tagged its command with, if it could be read
+        request_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+    // A pairing code requested by this connection
+    PairingCode {
+        code: String,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    },
+}
+
+/// What a command accomplished, reported back to the client in a `Response`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum CommandResult {
+    // The command was carried out
+    Done,
+    // A download was reserved; `url` serves it exactly once
+    Reservation {
+        token: u32,
+        url: String,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    },
+    // Every download in the session was revoked
+    Cleared {
+        count: usize,
+    },
+}
+
+/// Why a client's message was refused. These are part of the protocol, so existing codes
+/// must not be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The message was not valid JSON, or lacked fields its type requires
+    MalformedMessage,
+    // The message's type is not one this server knows
+    UnknownCommand,
+    // A hello was sent after the protocol had already been negotiated
+    UnexpectedHello,
+    // A field failed validation, e.g. an overlong label
+    InvalidPayload,
+    // The download or executable the command refers to does not exist
+    NotFound,
+    // A device or pairing code is unknown, already used or expired
+    InvalidCode,
+    // The session has used up its download quota
+    QuotaExceeded,
+    // The sessions could not be paired, e.g. because one has been purged
+    PairingFailed,
+    // The server failed to carry out the command
+    Internal,
+}
+
+/// A command that was refused, reported back to the client as an `Error`.
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn into_message(self, request_id: Option<String>) -> OutgoingMessage<'static> {
+        OutgoingMessage::Error {
+            request_id,
+            code: self.code,
+            message: self.message,
+        }
+    }
+}
+
+impl From<AppError> for CommandError {
+    fn from(error: AppError) -> Self {
+        let code = match error {
+            AppError::InvalidPayload { .. } => ErrorCode::InvalidPayload,
+            _ => ErrorCode::Internal,
+        };
+        Self::new(code, error.to_string())
+    }
 }
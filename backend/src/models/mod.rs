@@ -1,9 +1,41 @@
 mod build_logs;
+mod credentials;
+mod device;
 mod executable;
+mod export;
+mod labels;
 mod messages;
+mod pairing;
+mod protocol;
+mod queue;
+mod quota;
+mod reservation;
 mod session;
+mod usage;
 
 pub use build_logs::BuildLogs;
+pub use credentials::{Credentials, ExchangeRequest, Grant, TokenResponse};
+pub use device::{
+    DeviceAuthorizations, DeviceCodeResponse, DevicePoll, DeviceTokenRequest, DEVICE_CODE_LIFETIME,
+    DEVICE_POLL_INTERVAL_SECS,
+};
 pub use executable::{Executable, ExecutableJson};
-pub use messages::{IncomingMessage, OutgoingMessage};
-pub use session::Session;
+pub use export::{
+    SessionExport, SignedExport, DEFAULT_EXPORT_MAX_AGE, EXPORT_VERSION, MAX_EXPORT_BODY_SIZE,
+};
+pub use labels::{normalize_filename, normalize_label, normalize_notes, normalize_tags};
+pub use messages::{
+    CommandError, CommandResult, ErrorCode, IncomingEnvelope, IncomingMessage, OutgoingMessage,
+};
+pub use pairing::{PairingCodes, PAIRING_CODE_LIFETIME};
+pub use protocol::{
+    Heartbeat, Protocol, ServerInfo, HELLO_TIMEOUT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    SERVER_CAPABILITIES, STATE_DIFFS_VERSION,
+};
+pub use queue::{OverflowPolicy, SendError, SendQueue, SendQueuePolicy};
+pub use quota::{DownloadQuota, QuotaExceeded, QuotaKind, QuotaUsage};
+pub use reservation::{Reservation, Reservations, RESERVATION_LIFETIME};
+pub use session::{
+    Connection, PendingAlert, Session, SessionChange, SessionDownload, SessionId, TokenState,
+};
+pub use usage::{ClientContext, UsageEvent, UsageOutcome, MAX_CONTEXT_BODY_SIZE};
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use super::device::{generate_user_code, normalize_user_code};
+use super::session::SessionId;
+
+/// How long a pairing code can be redeemed for after it is issued.
+pub const PAIRING_CODE_LIFETIME: chrono::Duration = chrono::Duration::minutes(5);
+
+#[derive(Debug)]
+struct PairingCode {
+    session_id: SessionId,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Codes that let another browser join a session, keyed by code.
+///
+/// These are short-lived and only useful to browsers that are currently connected, so they
+/// are kept in memory rather than in storage.
+#[derive(Debug, Default)]
+pub struct PairingCodes {
+    pending: HashMap<String, PairingCode>,
+}
+
+impl PairingCodes {
+    // Issue a pairing code for a session, returning it along with its expiry
+    pub fn start(&mut self, session_id: SessionId) -> (String, chrono::DateTime<chrono::Utc>) {
+        let now = chrono::Utc::now();
+        self.pending.retain(|_, code| code.expires_at > now);
+
+        let code = generate_user_code();
+        let expires_at = now + PAIRING_CODE_LIFETIME;
+        self.pending.insert(
+            code.clone(),
+            PairingCode {
+                session_id,
+                expires_at,
+            },
+        );
+
+        (code, expires_at)
+    }
+
+    // Consume a pairing code, returning the session it was issued for if still valid
+    pub fn redeem(&mut self, code: &str) -> Option<SessionId> {
+        self.pending
+            .remove(&normalize_user_code(code))
+            .filter(|code| code.expires_at > chrono::Utc::now())
+            .map(|code| code.session_id)
+    }
+}
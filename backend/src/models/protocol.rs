@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// The newest WebSocket protocol version this server speaks.
+///
+/// Version 1 is the original protocol, spoken by clients that connect without a `hello`.
+/// Version 2 introduced the `hello` exchange.
+/// Version 3 replaced most `state` broadcasts with incremental, sequenced events.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// The oldest protocol version still served.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// The first protocol version whose clients receive incremental events rather than full state.
+pub const STATE_DIFFS_VERSION: u32 = 3;
+/// How long a connection that announced a `hello` (with `/ws?hello`) may take to send it
+/// before it is treated as version 1. Connections that don't announce one are served version 1
+/// straight away, so clients predating the handshake never wait on it.
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Optional features this server offers, advertised in its `hello`.
+pub const SERVER_CAPABILITIES: &[&str] = &[
+    "command-errors",
+    "device-codes",
+    "download-labels",
+    "download-quotas",
+    "pairing",
+    "session-export",
+    "state-diffs",
+];
+
+/// How often the server pings each WebSocket connection, and how long it waits for a pong.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    // Connections that don't answer a ping within this long are dropped
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Identifies the server build a client is talking to.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+impl ServerInfo {
+    pub fn current() -> Self {
+        Self {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+/// What was agreed with a connection during the `hello` exchange.
+#[derive(Debug, Clone)]
+pub struct Protocol {
+    pub version: u32,
+}
+
+impl Protocol {
+    /// The protocol assumed for clients that never say `hello`.
+    pub fn legacy() -> Self {
+        Self { version: 1 }
+    }
+
+    /// Agree on the newest version both sides speak, or explain why there is none.
+    pub fn negotiate(version: u32, min_version: Option<u32>) -> Result<Self, String> {
+        let agreed = version.min(PROTOCOL_VERSION);
+        if agreed < MIN_PROTOCOL_VERSION {
+            return Err(format!(
+                "protocol version {} is no longer supported",
+                version
+            ));
+        }
+        if let Some(min_version) = min_version.filter(|min| *min > PROTOCOL_VERSION) {
+            return Err(format!(
+                "client requires protocol version {}, server speaks at most {}",
+                min_version, PROTOCOL_VERSION
+            ));
+        }
+
+        Ok(Self { version: agreed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_clients_are_downgraded_to_the_server_version() {
+        let protocol = Protocol::negotiate(PROTOCOL_VERSION + 5, Some(1)).unwrap();
+
+        assert_eq!(protocol.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn older_supported_clients_keep_their_version() {
+        let protocol = Protocol::negotiate(MIN_PROTOCOL_VERSION, None).unwrap();
+
+        assert_eq!(protocol.version, MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn unsupported_versions_are_refused() {
+        assert!(Protocol::negotiate(MIN_PROTOCOL_VERSION - 1, None).is_err());
+        assert!(Protocol::negotiate(PROTOCOL_VERSION + 1, Some(PROTOCOL_VERSION + 1)).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use salvo::websocket::Message;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+use crate::metrics::Metrics;
+use crate::storage::lock;
+
+/// What happens when a message is sent to a connection whose queue is full.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// The oldest queued message is discarded to make room.
+    DropOldest,
+    /// Only the newest state snapshot is kept queued; if the queue is still full, the oldest
+    /// message is discarded.
+    #[default]
+    Coalesce,
+    /// The connection is closed, leaving the client to reconnect and catch up.
+    Disconnect,
+}
+
+/// Limits on the messages waiting to be written to each WebSocket connection.
+#[derive(Debug, Clone, Copy)]
+pub struct SendQueuePolicy {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for SendQueuePolicy {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// Why a message could not be queued.
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error("connection is closed")]
+    Closed,
+    #[error("send queue is full, connection closed")]
+    Overflow,
+}
+
+struct Queued {
+    message: Message,
+    // Whether this is a full state snapshot, superseded by any later one
+    snapshot: bool,
+}
+
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<Queued>,
+    closed: bool,
+}
+
+struct QueueInner {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    policy: SendQueuePolicy,
+    // The owning server's metrics, which track depth across all of its queues
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for QueueInner {
+    fn drop(&mut self) {
+        let remaining = lock(&self.state).messages.len();
+        self.metrics
+            .ws_send_queue_depth
+            .fetch_sub(remaining as u64, Ordering::Relaxed);
+    }
+}
+
+/// The bounded queue of messages waiting to be written to one WebSocket connection.
+///
+/// Handles are cheap to clone; the session holds one to send, and the connection's writer
+/// task holds another to receive.
+#[derive(Clone)]
+pub struct SendQueue(Arc<QueueInner>);
+
+impl std::fmt::Debug for SendQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendQueue")
+            .field("depth", &self.depth())
+            .field("policy", &self.0.policy)
+            .finish()
+    }
+}
+
+impl SendQueue {
+    pub fn new(policy: SendQueuePolicy, metrics: Arc<Metrics>) -> Self {
+        Self(Arc::new(QueueInner {
+            state: Mutex::default(),
+            notify: Notify::new(),
+            policy: SendQueuePolicy {
+                capacity: policy.capacity.max(1),
+                ..policy
+            },
+            metrics,
+        }))
+    }
+
+    /// Queues a message, applying the overflow policy if the queue is full.
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        self.push(message, false)
+    }
+
+    /// Queues a full state snapshot, which coalescing queues may merge with earlier ones.
+    pub fn send_snapshot(&self, message: Message) -> Result<(), SendError> {
+        self.push(message, true)
+    }
+
+    fn push(&self, message: Message, snapshot: bool) -> Result<(), SendError> {
+        let policy = self.0.policy;
+        let metrics = &self.0.metrics;
+        let mut state = lock(&self.0.state);
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+
+        let mut dropped = 0;
+        if snapshot && policy.overflow == OverflowPolicy::Coalesce {
+            let before = state.messages.len();
+            state.messages.retain(|queued| !queued.snapshot);
+            dropped += before - state.messages.len();
+        }
+
+        if state.messages.len() >= policy.capacity {
+            match policy.overflow {
+                OverflowPolicy::DropOldest | OverflowPolicy::Coalesce => {
+                    state.messages.pop_front();
+                    dropped += 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    metrics
+                        .ws_send_queue_depth
+                        .fetch_sub(state.messages.len() as u64, Ordering::Relaxed);
+                    state.messages.clear();
+                    state.closed = true;
+                    drop(state);
+
+                    metrics
+                        .ws_slow_disconnects_total
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.0.notify.notify_one();
+                    return Err(SendError::Overflow);
+                }
+            }
+        }
+
+        state.messages.push_back(Queued { message, snapshot });
+        // Adjusted under the lock, so the writer can't take the message before it is counted
+        metrics.ws_send_queue_depth.fetch_add(1, Ordering::Relaxed);
+        if dropped > 0 {
+            metrics
+                .ws_send_queue_depth
+                .fetch_sub(dropped as u64, Ordering::Relaxed);
+            metrics
+                .ws_send_queue_dropped_total
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+        drop(state);
+
+        self.0.notify.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next message to write. Returns `None` once the queue is closed; messages
+    /// queued before a graceful close are still delivered first.
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            {
+                let mut state = lock(&self.0.state);
+                if let Some(queued) = state.messages.pop_front() {
+                    self.0
+                        .metrics
+                        .ws_send_queue_depth
+                        .fetch_sub(1, Ordering::Relaxed);
+                    return Some(queued.message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.0.notify.notified().await;
+        }
+    }
+
+    /// Stops accepting messages. The writer finishes what is already queued, then stops.
+    pub fn close(&self) {
+        lock(&self.0.state).closed = true;
+        self.0.notify.notify_one();
+    }
+
+    /// Number of messages waiting to be written.
+    pub fn depth(&self) -> usize {
+        lock(&self.0.state).messages.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(capacity: usize, overflow: OverflowPolicy) -> SendQueue {
+        SendQueue::new(SendQueuePolicy { capacity, overflow }, Arc::default())
+    }
+
+    async fn drain(queue: &SendQueue) -> Vec<String> {
+        queue.close();
+        let mut texts = Vec::new();
+        while let Some(message) = queue.recv().await {
+            texts.push(message.to_str().unwrap().to_string());
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_the_oldest_message() {
+        let queue = queue(2, OverflowPolicy::DropOldest);
+        for text in ["a", "b", "c"] {
+            queue.send(Message::text(text)).unwrap();
+        }
+
+        assert_eq!(drain(&queue).await, ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn coalescing_keeps_only_the_newest_snapshot() {
+        let queue = queue(4, OverflowPolicy::Coalesce);
+        queue.send_snapshot(Message::text("state 1")).unwrap();
+        queue.send(Message::text("event")).unwrap();
+        queue.send_snapshot(Message::text("state 2")).unwrap();
+
+        assert_eq!(drain(&queue).await, ["event", "state 2"]);
+    }
+
+    #[tokio::test]
+    async fn overflow_disconnects_when_configured() {
+        let queue = queue(1, OverflowPolicy::Disconnect);
+        queue.send(Message::text("a")).unwrap();
+
+        assert!(matches!(
+            queue.send(Message::text("b")),
+            Err(SendError::Overflow)
+        ));
+        assert_eq!(queue.depth(), 0);
+        assert!(queue.recv().await.is_none());
+        assert!(matches!(
+            queue.send(Message::text("c")),
+            Err(SendError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn close_delivers_queued_messages_first() {
+        let queue = queue(4, OverflowPolicy::default());
+        queue.send(Message::text("a")).unwrap();
+        queue.close();
+
+        assert!(matches!(
+            queue.send(Message::text("b")),
+            Err(SendError::Closed)
+        ));
+        assert_eq!(queue.recv().await.unwrap().to_str().unwrap(), "a");
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn recv_wakes_for_a_later_message() {
+        let queue = queue(4, OverflowPolicy::default());
+        let writer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.recv().await }
+        });
+        tokio::task::yield_now().await;
+        queue.send(Message::text("a")).unwrap();
+
+        let message = writer.await.unwrap().unwrap();
+        assert_eq!(message.to_str().unwrap(), "a");
+    }
+}
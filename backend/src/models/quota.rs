@@ -0,0 +1,66 @@
+use serde::Serialize;
+
+/// Window over which `DownloadQuota::max_per_hour` is counted.
+pub const QUOTA_WINDOW: chrono::Duration = chrono::Duration::hours(1);
+
+/// Limits on the downloads a single session may create. `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct DownloadQuota {
+    // Downloads that exist and have not expired
+    pub max_live: Option<usize>,
+    // Downloads created within the last hour, including since-deleted ones
+    pub max_per_hour: Option<usize>,
+    // Executable bytes served over the session's lifetime
+    pub max_bytes: Option<u64>,
+}
+
+/// A session's consumption of its quota, reported alongside the limits so clients can tell
+/// ahead of time whether another download will be refused.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    pub live: usize,
+    pub last_hour: usize,
+    pub bytes_served: u64,
+    pub limits: DownloadQuota,
+}
+
+/// Which limit of a `DownloadQuota` was hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKind {
+    LiveDownloads,
+    HourlyDownloads,
+    Bytes,
+}
+
+/// The body returned when a download is refused for exceeding the session's quota.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaExceeded {
+    pub error: &'static str,
+    pub quota: QuotaKind,
+    pub limit: u64,
+    pub usage: QuotaUsage,
+}
+
+impl QuotaExceeded {
+    pub fn new(quota: QuotaKind, limit: u64, usage: QuotaUsage) -> Self {
+        Self {
+            error: "quota_exceeded",
+            quota,
+            limit,
+            usage,
+        }
+    }
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.quota {
+            QuotaKind::LiveDownloads => write!(f, "limit of {} live downloads reached", self.limit),
+            QuotaKind::HourlyDownloads => {
+                write!(f, "limit of {} downloads per hour reached", self.limit)
+            }
+            QuotaKind::Bytes => write!(f, "limit of {} bytes served reached", self.limit),
+        }
+    }
+}
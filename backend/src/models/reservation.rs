@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use super::session::SessionId;
+
+/// How long a reserved download's one-time URL remains usable.
+pub const RESERVATION_LIFETIME: chrono::Duration = chrono::Duration::minutes(10);
+
+/// A download created over the WebSocket, waiting to be fetched from its one-time URL.
+#[derive(Debug)]
+pub struct Reservation {
+    pub session_id: SessionId,
+    pub token: u32,
+    // The ID of the executable to serve, as in `/download/<id>`
+    pub executable: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Reserved downloads, keyed by the random ID in their one-time URL.
+///
+/// Reservations are short-lived, so they are kept in memory rather than in storage; the
+/// download itself is stored with its session as usual.
+#[derive(Debug, Default)]
+pub struct Reservations {
+    pending: HashMap<String, Reservation>,
+}
+
+impl Reservations {
+    // Reserve a download, returning the ID for its one-time URL along with its expiry
+    pub fn reserve(
+        &mut self,
+        session_id: SessionId,
+        token: u32,
+        executable: &str,
+    ) -> (String, chrono::DateTime<chrono::Utc>) {
+        let now = chrono::Utc::now();
+        self.pending
+            .retain(|_, reservation| reservation.expires_at > now);
+
+        let id = hex::encode(rand::random::<[u8; 16]>());
+        let expires_at = now + RESERVATION_LIFETIME;
+        self.pending.insert(
+            id.clone(),
+            Reservation {
+                session_id,
+                token,
+                executable: executable.to_string(),
+                expires_at,
+            },
+        );
+
+        (id, expires_at)
+    }
+
+    // Consume a reservation, returning it if it has not expired
+    pub fn take(&mut self, id: &str) -> Option<Reservation> {
+        self.pending
+            .remove(id)
+            .filter(|reservation| reservation.expires_at > chrono::Utc::now())
+    }
+}
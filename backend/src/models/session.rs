@@ -1,19 +1,119 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 
 use salvo::websocket::Message;
-use serde::Serialize;
-use tokio::sync::mpsc::UnboundedSender;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::Notify;
 
 use super::executable::Executable;
 use super::messages::OutgoingMessage;
+use super::protocol::{Protocol, STATE_DIFFS_VERSION};
+use super::queue::{SendError, SendQueue};
+use super::quota::{DownloadQuota, QuotaExceeded, QuotaKind, QuotaUsage, QUOTA_WINDOW};
+use super::usage::{ClientContext, UsageEvent, UsageOutcome, MAX_USAGE_EVENTS};
 
-/// Sender type for WebSocket connections
-pub type ConnectionSender = UnboundedSender<Result<Message, salvo::Error>>;
+/// Number of deleted downloads remembered per session, so later uses can be reported as revoked.
+const MAX_REVOKED_DOWNLOADS: usize = 16;
+/// Number of sequenced messages kept per session for clients that reconnect.
+const MAX_BUFFERED_EVENTS: usize = 64;
+/// Number of alerts queued per session while it has no open tabs.
+const MAX_PENDING_ALERTS: usize = 32;
 
-#[derive(Debug, Serialize, Clone)]
+/// An open WebSocket connection, along with the protocol negotiated for it.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub sender: SendQueue,
+    pub protocol: Protocol,
+}
+
+impl Connection {
+    fn queue(&self, message: Message, snapshot: bool) -> Result<(), SendError> {
+        if snapshot {
+            self.sender.send_snapshot(message)
+        } else {
+            self.sender.send(message)
+        }
+    }
+}
+
+/// A change to a session that clients are told about as an incremental event.
+#[derive(Debug, Clone, Copy)]
+pub enum SessionChange {
+    DownloadAdded(u32),
+    // The download was revoked or deleted
+    DownloadRemoved(u32),
+    // The download's label, notes, tags, filename or machine binding changed
+    DownloadUpdated(u32),
+    TokenUsed(u32),
+}
+
+/// A token alert that couldn't be delivered because the session had no open tabs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingAlert {
+    pub seq: u64,
+    pub token: u32,
+    pub context: Option<ClientContext>,
+    pub machine_mismatch: bool,
+    pub queued_at: chrono::DateTime<chrono::Utc>,
+}
+
+// A sequenced message, kept so it can be replayed to a client that reconnects
+#[derive(Debug, Clone)]
+enum BufferedEvent {
+    // An incremental change, as sent to clients that negotiated state diffs
+    Change(String),
+    Alert(String),
+    // A change only described by a full state broadcast, which can't be replayed
+    Snapshot,
+}
+
+/// A random 128-bit session identifier, rendered as 32 hex characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(u128);
+
+impl SessionId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl std::str::FromStr for SessionId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 32 {
+            return Err(anyhow::anyhow!("Session ID must be 32 hex characters"));
+        }
+        Ok(Self(u128::from_str_radix(s, 16)?))
+    }
+}
+
+// Serialized as a string, since JavaScript numbers can't hold 128 bits
+impl Serialize for SessionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SessionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Session {
-    pub id: u32,
+    pub id: SessionId,
     pub downloads: Vec<SessionDownload>,
+    // Recently deleted downloads, kept so their usage history survives revocation
+    pub revoked: VecDeque<SessionDownload>,
 
     pub first_seen: chrono::DateTime<chrono::Utc>,
     // The last time a request OR websocket message from/to this session was made
@@ -21,13 +121,58 @@ pub struct Session {
     // The last time a request was made with this session
     pub last_request: chrono::DateTime<chrono::Utc>,
 
+    // When executable downloads were created within the quota window, oldest first
+    #[serde(default)]
+    pub recent_downloads: VecDeque<chrono::DateTime<chrono::Utc>>,
+    // Total executable bytes served to this session
+    #[serde(default)]
+    pub bytes_served: u64,
+
+    // IDs of sessions that were paired into this one; their cookies resolve here
+    #[serde(default)]
+    pub aliases: Vec<SessionId>,
+
+    // Numbers the changes broadcast to clients, so they can tell when they've missed one
+    #[serde(default)]
+    pub seq: u64,
+    // Alerts raised while no tab was open, delivered to the next tab that connects
+    #[serde(default)]
+    pub pending_alerts: VecDeque<PendingAlert>,
+    // The most recent sequenced messages, oldest first
+    #[serde(skip)]
+    events: VecDeque<(u64, BufferedEvent)>,
+    // Woken whenever a sequenced message is recorded, for clients waiting on the next one
+    #[serde(skip)]
+    changed: Arc<Notify>,
+
     /// Multiple WebSocket connections per session (multi-tab support)
     /// Key is a random connection ID, value is the sender channel
-    #[serde(skip_serializing)]
-    pub connections: HashMap<u64, ConnectionSender>,
+    #[serde(skip)]
+    pub connections: HashMap<u64, Connection>,
 }
 
 impl Session {
+    pub fn new(id: SessionId) -> Self {
+        let now = chrono::Utc::now();
+
+        Self {
+            id,
+            downloads: Vec::new(),
+            revoked: VecDeque::new(),
+            first_seen: now,
+            last_seen: now,
+            last_request: now,
+            recent_downloads: VecDeque::new(),
+            bytes_served: 0,
+            aliases: Vec::new(),
+            seq: 0,
+            pending_alerts: VecDeque::new(),
+            events: VecDeque::new(),
+            changed: Arc::default(),
+            connections: HashMap::new(),
+        }
+    }
+
     // Update the last seen time(s) for the session
     pub fn seen(&mut self, socket: bool) {
         self.last_seen = chrono::Utc::now();
@@ -36,43 +181,324 @@ impl Session {
         }
     }
 
-    // Add a download to the session
-    pub fn add_download(&mut self, exe: &Executable) -> &SessionDownload {
+    // Report how much of a quota this session has used
+    pub fn quota_usage(&self, quota: &DownloadQuota) -> QuotaUsage {
+        let window_start = chrono::Utc::now() - QUOTA_WINDOW;
+
+        QuotaUsage {
+            live: self.downloads.iter().filter(|d| d.is_live()).count(),
+            last_hour: self
+                .recent_downloads
+                .iter()
+                .filter(|created| **created > window_start)
+                .count(),
+            bytes_served: self.bytes_served,
+            limits: *quota,
+        }
+    }
+
+    // Check whether serving another executable of `size` bytes would exceed a quota
+    pub fn check_quota(&mut self, quota: &DownloadQuota, size: u64) -> Result<(), QuotaExceeded> {
+        self.check_quota_for(quota, 1, size)
+    }
+
+    // Check whether adding `count` more downloads, totalling `size` bytes, would exceed a quota
+    pub fn check_quota_for(
+        &mut self,
+        quota: &DownloadQuota,
+        count: usize,
+        size: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let window_start = chrono::Utc::now() - QUOTA_WINDOW;
+        while self
+            .recent_downloads
+            .front()
+            .is_some_and(|created| *created <= window_start)
+        {
+            self.recent_downloads.pop_front();
+        }
+
+        let usage = self.quota_usage(quota);
+        if let Some(limit) = quota
+            .max_live
+            .filter(|limit| usage.live.saturating_add(count) > *limit)
+        {
+            return Err(QuotaExceeded::new(
+                QuotaKind::LiveDownloads,
+                limit as u64,
+                usage,
+            ));
+        }
+        if let Some(limit) = quota
+            .max_per_hour
+            .filter(|limit| usage.last_hour.saturating_add(count) > *limit)
+        {
+            return Err(QuotaExceeded::new(
+                QuotaKind::HourlyDownloads,
+                limit as u64,
+                usage,
+            ));
+        }
+        if let Some(limit) = quota
+            .max_bytes
+            .filter(|limit| usage.bytes_served.saturating_add(size) > *limit)
+        {
+            return Err(QuotaExceeded::new(QuotaKind::Bytes, limit, usage));
+        }
+
+        Ok(())
+    }
+
+    // Add a download to the session, counting it against the session's quota
+    pub fn add_download(
+        &mut self,
+        exe: &Executable,
+        lifetime: Option<chrono::Duration>,
+    ) -> &SessionDownload {
         let token: u32 = rand::random();
+        let filename = format!(
+            "{}-{:08x}{}{}",
+            exe.name,
+            token,
+            if !exe.extension.is_empty() { "." } else { "" },
+            exe.extension
+        );
+
+        self.recent_downloads.push_back(chrono::Utc::now());
+        self.bytes_served += exe.data.len() as u64;
+        self.insert_download(token, filename, lifetime)
+    }
+
+    // Add a download for a device that authorized itself with a user code, rather than
+    // receiving its token embedded in a served executable
+    pub fn add_device_download(
+        &mut self,
+        user_code: &str,
+        token: u32,
+        lifetime: Option<chrono::Duration>,
+    ) -> &SessionDownload {
+        self.insert_download(token, format!("device-{}", user_code), lifetime)
+    }
+
+    fn insert_download(
+        &mut self,
+        token: u32,
+        filename: String,
+        lifetime: Option<chrono::Duration>,
+    ) -> &SessionDownload {
+        let now = chrono::Utc::now();
 
         let download = SessionDownload {
             token,
-            filename: format!(
-                "{}-{:08x}{}{}",
-                exe.name,
-                token,
-                if !exe.extension.is_empty() { "." } else { "" },
-                exe.extension
-            ),
-            last_used: chrono::Utc::now(),
-            download_time: chrono::Utc::now(),
+            filename,
+            last_used: now,
+            download_time: now,
+            expires_at: lifetime.map(|lifetime| now + lifetime),
+            machine_fingerprint: None,
+            usage: VecDeque::new(),
+            label: None,
+            notes: None,
+            tags: Vec::new(),
+            revoked_at: None,
         };
 
         self.downloads.push(download);
         self.downloads.last().unwrap()
     }
 
-    // Delete a download from the session
+    // Absorb another session paired into this one, taking its downloads and connections
+    pub fn merge(&mut self, other: Session) {
+        self.downloads.extend(other.downloads);
+        self.revoked.extend(other.revoked);
+        while self.revoked.len() > MAX_REVOKED_DOWNLOADS {
+            self.revoked.pop_front();
+        }
+
+        self.recent_downloads.extend(other.recent_downloads);
+        self.recent_downloads.make_contiguous().sort();
+        self.bytes_served += other.bytes_served;
+
+        self.aliases.push(other.id);
+        self.aliases.extend(other.aliases);
+        self.seq = self.seq.max(other.seq);
+        self.pending_alerts.extend(other.pending_alerts);
+        while self.pending_alerts.len() > MAX_PENDING_ALERTS {
+            self.pending_alerts.pop_front();
+        }
+        self.connections.extend(other.connections);
+
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.last_request = self.last_request.max(other.last_request);
+
+        // Requests waiting on the other session retry, and find their way to this one
+        other.changed.notify_waiters();
+    }
+
+    // Take back downloads from an export, skipping any this session already holds
+    // Restored live downloads count towards the hourly quota, like newly created ones
+    // Returns the number of downloads restored
+    pub fn import(
+        &mut self,
+        downloads: Vec<SessionDownload>,
+        revoked: VecDeque<SessionDownload>,
+    ) -> usize {
+        let before = self.downloads.len() + self.revoked.len();
+
+        for download in downloads {
+            if self.token_state(download.token).is_none() {
+                if download.is_live() {
+                    self.recent_downloads.push_back(chrono::Utc::now());
+                }
+                self.downloads.push(download);
+            }
+        }
+        for download in revoked {
+            if self.token_state(download.token).is_none() {
+                self.revoked.push_back(download);
+            }
+        }
+        while self.revoked.len() > MAX_REVOKED_DOWNLOADS {
+            self.revoked.pop_front();
+        }
+
+        (self.downloads.len() + self.revoked.len()).saturating_sub(before)
+    }
+
+    // Forget a download entirely, whether live or revoked, returning whether it was held
+    pub fn remove_download(&mut self, token: u32) -> bool {
+        let before = self.downloads.len() + self.revoked.len();
+        self.downloads.retain(|d| d.token != token);
+        self.revoked.retain(|d| d.token != token);
+        self.downloads.len() + self.revoked.len() != before
+    }
+
+    // Delete a download from the session, remembering it as revoked
     // Returns true if the download was deleted, false if it was not found
     pub fn delete_download(&mut self, token: u32) -> bool {
         if let Some(index) = self.downloads.iter().position(|d| d.token == token) {
-            self.downloads.remove(index);
+            let download = self.downloads.remove(index);
+            self.revoked.push_back(download);
+            if self.revoked.len() > MAX_REVOKED_DOWNLOADS {
+                self.revoked.pop_front();
+            }
             true
         } else {
-            tracing::warn!("Attempted to delete non-existent download token: {}", token);
             false
         }
     }
 
+    // Revoke a download's token, keeping the download listed with its history
+    // Returns true if the download was found, whether or not it was already revoked
+    pub fn revoke_download(&mut self, token: u32) -> bool {
+        match self.download_mut(token) {
+            Some(download) => {
+                download.revoked_at.get_or_insert_with(chrono::Utc::now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Find a download by token, including revoked ones
+    pub fn download(&self, token: u32) -> Option<&SessionDownload> {
+        self.downloads
+            .iter()
+            .chain(self.revoked.iter())
+            .find(|d| d.token == token)
+    }
+
+    // Find a live download by token for editing
+    pub fn download_mut(&mut self, token: u32) -> Option<&mut SessionDownload> {
+        self.downloads.iter_mut().find(|d| d.token == token)
+    }
+
+    // Revoke every download in the session, returning how many were revoked
+    pub fn clear_downloads(&mut self) -> usize {
+        let tokens: Vec<u32> = self.downloads.iter().map(|d| d.token).collect();
+        for token in &tokens {
+            self.delete_download(*token);
+        }
+        tokens.len()
+    }
+
+    // Determine whether a token belongs to this session, and if it is still usable
+    pub fn token_state(&self, token: u32) -> Option<TokenState> {
+        if let Some(download) = self.downloads.iter().find(|d| d.token == token) {
+            return Some(if download.is_revoked() {
+                TokenState::Revoked
+            } else if download.is_expired() {
+                TokenState::Expired
+            } else {
+                TokenState::Active
+            });
+        }
+
+        self.revoked
+            .iter()
+            .any(|d| d.token == token)
+            .then_some(TokenState::Revoked)
+    }
+
+    // Bind a token to a machine on first use, returning whether the fingerprint matches the binding
+    // A download that has not been bound yet is bound to whatever fingerprint is presented
+    pub fn bind_machine(&mut self, token: u32, fingerprint: Option<&str>) -> bool {
+        let Some(download) = self.downloads.iter_mut().find(|d| d.token == token) else {
+            return false;
+        };
+
+        match &download.machine_fingerprint {
+            Some(bound) => fingerprint == Some(bound.as_str()),
+            None => {
+                if let Some(fingerprint) = fingerprint {
+                    tracing::info!(
+                        session_id = %self.id,
+                        dl_token = token,
+                        "Token bound to machine"
+                    );
+                    download.machine_fingerprint = Some(fingerprint.to_string());
+                }
+                true
+            }
+        }
+    }
+
+    // Clear the machine binding of a download, so the next use binds it again
+    // Returns true if a binding was cleared
+    pub fn reset_machine_binding(&mut self, token: u32) -> bool {
+        match self.downloads.iter_mut().find(|d| d.token == token) {
+            Some(download) => download.machine_fingerprint.take().is_some(),
+            None => {
+                tracing::warn!(
+                    "Attempted to reset binding of non-existent download token: {}",
+                    token
+                );
+                false
+            }
+        }
+    }
+
+    // Record a use of a download token, returning false if the token is unknown
+    pub fn record_usage(&mut self, token: u32, event: UsageEvent) -> bool {
+        match self
+            .downloads
+            .iter_mut()
+            .chain(self.revoked.iter_mut())
+            .find(|d| d.token == token)
+        {
+            Some(download) => {
+                download.record_usage(event);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Register a new WebSocket connection and return its ID
-    pub fn add_connection(&mut self, tx: ConnectionSender) -> u64 {
+    pub fn add_connection(&mut self, sender: SendQueue, protocol: Protocol) -> u64 {
         let connection_id: u64 = rand::random();
-        self.connections.insert(connection_id, tx);
+        self.connections
+            .insert(connection_id, Connection { sender, protocol });
         tracing::debug!(
             "Added connection {} to session {} (total: {})",
             connection_id,
@@ -96,19 +522,33 @@ impl Session {
     /// Broadcast a message to all connected WebSocket clients
     /// Returns the number of connections that received the message
     pub fn send_message(&mut self, message: OutgoingMessage) -> Result<usize, anyhow::Error> {
+        let json = serde_json::to_string(&message)?;
+        let snapshot = matches!(message, OutgoingMessage::State { .. });
+        self.broadcast(|_| (&json, snapshot))
+    }
+
+    // Queue a message on every connection, choosing per connection which serialized message
+    // it gets and whether that is a state snapshot
+    fn broadcast<'a>(
+        &mut self,
+        message_for: impl Fn(&Connection) -> (&'a String, bool),
+    ) -> Result<usize, anyhow::Error> {
         if self.connections.is_empty() {
             return Err(anyhow::anyhow!("Session {} has no connections", self.id));
         }
 
-        let json = serde_json::to_string(&message)?;
         let mut dead_connections = Vec::new();
         let mut sent_count = 0;
 
-        for (&conn_id, tx) in &self.connections {
-            match tx.send(Ok(Message::text(json.clone()))) {
+        for (&conn_id, connection) in &self.connections {
+            let (json, snapshot) = message_for(connection);
+            match connection.queue(Message::text(json.clone()), snapshot) {
                 Ok(_) => sent_count += 1,
-                Err(_) => {
-                    // Channel closed, mark for removal
+                Err(e) => {
+                    if let SendError::Overflow = e {
+                        tracing::warn!(session_id = %self.id, connection_id = conn_id, "Dropping WebSocket connection that fell too far behind");
+                    }
+                    // Queue closed, mark for removal
                     dead_connections.push(conn_id);
                 }
             }
@@ -134,12 +574,282 @@ impl Session {
         Ok(sent_count)
     }
 
-    pub fn send_state(&mut self) -> Result<usize, anyhow::Error> {
-        let message = OutgoingMessage::State {
-            session: self.clone(),
+    fn state_message(&self, quota: &DownloadQuota) -> OutgoingMessage<'_> {
+        OutgoingMessage::State {
+            session: self,
+            quota: self.quota_usage(quota),
+        }
+    }
+
+    /// Broadcast the full session state, e.g. after a change too broad to describe as an event.
+    pub fn send_state(&mut self, quota: &DownloadQuota) -> Result<usize, anyhow::Error> {
+        self.seq += 1;
+        self.record(BufferedEvent::Snapshot);
+        let state = serde_json::to_string(&self.state_message(quota))?;
+        self.broadcast(|_| (&state, true))
+    }
+
+    /// Alert every connection that a download's token was used. The alert is kept, so a
+    /// client that reconnects shortly afterwards still hears about it, and if no tab is open
+    /// it is queued for the next one that connects.
+    pub fn send_alert(
+        &mut self,
+        token: u32,
+        context: Option<ClientContext>,
+        machine_mismatch: bool,
+    ) -> Result<UsageOutcome, anyhow::Error> {
+        self.seq += 1;
+
+        let message = self.alert_message(self.seq, token, context.clone(), machine_mismatch, None);
+        let json = serde_json::to_string(&message)?;
+        self.record(BufferedEvent::Alert(json.clone()));
+
+        if self.broadcast(|_| (&json, false)).is_ok() {
+            return Ok(UsageOutcome::Delivered);
+        }
+
+        self.pending_alerts.push_back(PendingAlert {
+            seq: self.seq,
+            token,
+            context,
+            machine_mismatch,
+            queued_at: chrono::Utc::now(),
+        });
+        if self.pending_alerts.len() > MAX_PENDING_ALERTS {
+            self.pending_alerts.pop_front();
+        }
+        Ok(UsageOutcome::Queued)
+    }
+
+    fn alert_message(
+        &self,
+        seq: u64,
+        token: u32,
+        context: Option<ClientContext>,
+        machine_mismatch: bool,
+        queued_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> OutgoingMessage<'static> {
+        let download = self.download(token);
+        OutgoingMessage::TokenAlert {
+            seq,
+            token,
+            context,
+            machine_mismatch,
+            label: download.and_then(|download| download.label.clone()),
+            tags: download
+                .map(|download| download.tags.clone())
+                .unwrap_or_default(),
+            queued_at,
+        }
+    }
+
+    /// Bring a newly registered connection up to date: the full state, or the events it
+    /// missed if it is resuming from `last_seq`, followed by any alerts queued while no tab
+    /// was open.
+    pub fn catch_up(
+        &mut self,
+        connection_id: u64,
+        last_seq: Option<u64>,
+        quota: &DownloadQuota,
+    ) -> Result<(), anyhow::Error> {
+        match last_seq {
+            Some(last_seq) => self.replay_to(connection_id, last_seq, quota)?,
+            None => self.send_state_to(connection_id, quota)?,
+        }
+
+        while let Some(alert) = self.pending_alerts.front() {
+            // A resuming client was already sent alerts that are still buffered
+            let replayed = last_seq.is_some_and(|last_seq| {
+                alert.seq > last_seq
+                    && self.events.iter().any(|(seq, event)| {
+                        *seq == alert.seq && matches!(event, BufferedEvent::Alert(_))
+                    })
+            });
+            if !replayed {
+                let message = self.alert_message(
+                    alert.seq,
+                    alert.token,
+                    alert.context.clone(),
+                    alert.machine_mismatch,
+                    Some(alert.queued_at),
+                );
+                self.send_message_to(connection_id, &message)?;
+            }
+            self.pending_alerts.pop_front();
+        }
+
+        Ok(())
+    }
+
+    // Keep a sequenced message for replay, under the current sequence number
+    fn record(&mut self, event: BufferedEvent) {
+        self.events.push_back((self.seq, event));
+        if self.events.len() > MAX_BUFFERED_EVENTS {
+            self.events.pop_front();
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// A handle that is woken whenever the session records a new sequenced message.
+    pub fn changed(&self) -> Arc<Notify> {
+        self.changed.clone()
+    }
+
+    /// The sequenced messages after `since`, for clients that poll rather than hold a
+    /// connection. If some can't be replayed, the full state takes their place, followed by
+    /// the alerts.
+    pub fn events_since(
+        &self,
+        since: u64,
+        quota: &DownloadQuota,
+    ) -> Result<Vec<serde_json::Value>, anyhow::Error> {
+        let (missed, replayable) = self.missed_since(since);
+
+        let mut events = Vec::new();
+        if !replayable {
+            events.push(serde_json::to_value(self.state_message(quota))?);
+        }
+        for event in missed {
+            match event {
+                BufferedEvent::Change(json) if replayable => {
+                    events.push(serde_json::from_str(json)?)
+                }
+                BufferedEvent::Alert(json) => events.push(serde_json::from_str(json)?),
+                _ => {}
+            }
+        }
+        Ok(events)
+    }
+
+    // The buffered events after `last_seq`, and whether they describe every change since then
+    fn missed_since(&self, last_seq: u64) -> (Vec<&BufferedEvent>, bool) {
+        let missed: Vec<&BufferedEvent> = self
+            .events
+            .iter()
+            .filter(|(seq, _)| *seq > last_seq)
+            .map(|(_, event)| event)
+            .collect();
+
+        // Every sequence number is recorded, so the buffer is complete if it reaches back to
+        // just after `last_seq`. The client chooses `last_seq`, so it may be anything at all.
+        let reaches_back = match self.events.front() {
+            Some((first, _)) => *first <= last_seq.saturating_add(1),
+            None => last_seq == self.seq,
+        };
+        let replayable = last_seq <= self.seq
+            && reaches_back
+            && !missed
+                .iter()
+                .any(|event| matches!(event, BufferedEvent::Snapshot));
+
+        (missed, replayable)
+    }
+
+    // Catch up a reconnecting connection that last saw `last_seq`.
+    // If every change since then can be replayed as an event, a `resumed` message is sent
+    // followed by those events. Otherwise the full state is sent, followed by any alerts the
+    // client missed.
+    fn replay_to(
+        &self,
+        connection_id: u64,
+        last_seq: u64,
+        quota: &DownloadQuota,
+    ) -> Result<(), anyhow::Error> {
+        let (missed, replayable) = self.missed_since(last_seq);
+
+        if replayable {
+            let resumed = OutgoingMessage::Resumed {
+                seq: last_seq,
+                replayed: missed.len(),
+            };
+            self.send_message_to(connection_id, &resumed)?;
+        } else {
+            self.send_state_to(connection_id, quota)?;
+        }
+
+        for event in missed {
+            match event {
+                BufferedEvent::Change(json) if replayable => {
+                    self.send_json_to(connection_id, json.clone(), false)?
+                }
+                BufferedEvent::Alert(json) => {
+                    self.send_json_to(connection_id, json.clone(), false)?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Broadcast a change to the session. Connections that negotiated state diffs receive a
+    /// small event; older ones receive the full state.
+    pub fn publish(
+        &mut self,
+        change: SessionChange,
+        quota: &DownloadQuota,
+    ) -> Result<usize, anyhow::Error> {
+        self.seq += 1;
+
+        let event = self
+            .change_event(change, quota)
+            .map(|event| serde_json::to_string(&event))
+            .transpose()?;
+        let needs_state = event.is_none()
+            || self
+                .connections
+                .values()
+                .any(|connection| connection.protocol.version < STATE_DIFFS_VERSION);
+        let state = if needs_state {
+            serde_json::to_string(&self.state_message(quota))?
+        } else {
+            String::new()
         };
 
-        self.send_message(message)
+        self.record(match &event {
+            Some(event) => BufferedEvent::Change(event.clone()),
+            None => BufferedEvent::Snapshot,
+        });
+
+        self.broadcast(|connection| match &event {
+            Some(event) if connection.protocol.version >= STATE_DIFFS_VERSION => (event, false),
+            _ => (&state, true),
+        })
+    }
+
+    // Describe a change as an event, or `None` if the download it refers to is gone
+    fn change_event(
+        &self,
+        change: SessionChange,
+        quota: &DownloadQuota,
+    ) -> Option<OutgoingMessage<'static>> {
+        let seq = self.seq;
+        let event = match change {
+            SessionChange::DownloadAdded(token) => OutgoingMessage::DownloadAdded {
+                seq,
+                download: self.download(token)?.clone(),
+                quota: self.quota_usage(quota),
+            },
+            SessionChange::DownloadRemoved(token) => OutgoingMessage::DownloadRemoved {
+                seq,
+                token,
+                quota: self.quota_usage(quota),
+            },
+            SessionChange::DownloadUpdated(token) => OutgoingMessage::DownloadUpdated {
+                seq,
+                download: self.download(token)?.clone(),
+            },
+            SessionChange::TokenUsed(token) => {
+                let download = self.download(token)?;
+                OutgoingMessage::TokenUsed {
+                    seq,
+                    token,
+                    last_used: download.last_used,
+                    usage: download.usage.back()?.clone(),
+                }
+            }
+        };
+        Some(event)
     }
 
     /// Send a message to a single connection by ID, leaving the others untouched.
@@ -150,7 +860,19 @@ impl Session {
         connection_id: u64,
         message: &OutgoingMessage,
     ) -> Result<(), anyhow::Error> {
-        let tx = self.connections.get(&connection_id).ok_or_else(|| {
+        let json = serde_json::to_string(message)?;
+        let snapshot = matches!(message, OutgoingMessage::State { .. });
+        self.send_json_to(connection_id, json, snapshot)
+    }
+
+    // Send an already serialized message to a single connection
+    fn send_json_to(
+        &self,
+        connection_id: u64,
+        json: String,
+        snapshot: bool,
+    ) -> Result<(), anyhow::Error> {
+        let connection = self.connections.get(&connection_id).ok_or_else(|| {
             anyhow::anyhow!(
                 "Connection {} not found in session {}",
                 connection_id,
@@ -158,25 +880,307 @@ impl Session {
             )
         })?;
 
-        let json = serde_json::to_string(message)?;
-        tx.send(Ok(Message::text(json)))
+        connection
+            .queue(Message::text(json), snapshot)
             .map_err(|e| anyhow::anyhow!("Error sending message: {}", e))
     }
 
     /// Send the current session state to a single connection.
-    pub fn send_state_to(&self, connection_id: u64) -> Result<(), anyhow::Error> {
-        let message = OutgoingMessage::State {
-            session: self.clone(),
-        };
+    pub fn send_state_to(
+        &self,
+        connection_id: u64,
+        quota: &DownloadQuota,
+    ) -> Result<(), anyhow::Error> {
+        self.send_message_to(connection_id, &self.state_message(quota))
+    }
+}
 
-        self.send_message_to(connection_id, &message)
+#[cfg(test)]
+impl Session {
+    // A new session holding a single device download, as most tests start from
+    pub fn with_download(token: u32) -> Self {
+        let mut session = Session::new(SessionId::random());
+        session.add_device_download("BCDF-GHJK", token, None);
+        session
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionDownload {
     pub token: u32,
     pub filename: String,
     pub last_used: chrono::DateTime<chrono::Utc>,
     pub download_time: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    // The fingerprint of the machine this token was first used from, if bound
+    pub machine_fingerprint: Option<String>,
+    // The most recent uses of this download's token, oldest first
+    pub usage: VecDeque<UsageEvent>,
+    // A user-assigned name, e.g. the machine the binary was copied to
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // When the token was revoked; a revoked download stays listed, along with its history
+    #[serde(default)]
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl SessionDownload {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+
+    // Whether the download still counts towards the session's live downloads
+    pub fn is_live(&self) -> bool {
+        !self.is_expired() && !self.is_revoked()
+    }
+
+    // Append a usage event, dropping the oldest once the ring is full
+    pub fn record_usage(&mut self, event: UsageEvent) {
+        if matches!(
+            event.outcome,
+            UsageOutcome::Delivered | UsageOutcome::Queued | UsageOutcome::Failed
+        ) {
+            self.last_used = event.timestamp;
+        }
+
+        self.usage.push_back(event);
+        if self.usage.len() > MAX_USAGE_EVENTS {
+            self.usage.pop_front();
+        }
+    }
+}
+
+/// Whether a token can still be used to notify its session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenState {
+    Active,
+    Expired,
+    Revoked,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SendQueuePolicy;
+
+    fn executable(size: usize) -> Executable {
+        Executable {
+            data: vec![0; size],
+            filename: "demo".to_string(),
+            name: "demo".to_string(),
+            extension: String::new(),
+            key_start: 0,
+            key_end: 0,
+        }
+    }
+
+    fn usage(outcome: UsageOutcome) -> UsageEvent {
+        UsageEvent::new(outcome, false, None, None, None)
+    }
+
+    #[test]
+    fn usage_history_keeps_only_the_newest_events() {
+        let mut session = Session::with_download(7);
+        for _ in 0..MAX_USAGE_EVENTS {
+            session.record_usage(7, usage(UsageOutcome::Failed));
+        }
+        session.record_usage(7, usage(UsageOutcome::Delivered));
+
+        let history = &session.downloads[0].usage;
+        assert_eq!(history.len(), MAX_USAGE_EVENTS);
+        assert_eq!(history.back().unwrap().outcome, UsageOutcome::Delivered);
+    }
+
+    #[test]
+    fn revoked_tokens_keep_recording_usage() {
+        let mut session = Session::with_download(7);
+        assert!(session.delete_download(7));
+
+        assert_eq!(session.token_state(7), Some(TokenState::Revoked));
+        assert!(session.record_usage(7, usage(UsageOutcome::Revoked)));
+        assert!(!session.record_usage(8, usage(UsageOutcome::Revoked)));
+        assert_eq!(session.revoked[0].usage.len(), 1);
+    }
+
+    #[test]
+    fn revoked_downloads_stay_listed_but_not_live() {
+        let mut session = Session::with_download(7);
+        session.record_usage(7, usage(UsageOutcome::Delivered));
+
+        assert!(session.revoke_download(7));
+        assert!(!session.revoke_download(8));
+        assert_eq!(session.token_state(7), Some(TokenState::Revoked));
+        assert_eq!(session.downloads[0].usage.len(), 1);
+        assert_eq!(session.quota_usage(&DownloadQuota::default()).live, 0);
+    }
+
+    #[test]
+    fn refused_uses_do_not_count_as_last_used() {
+        let mut session = Session::with_download(7);
+        let last_used = session.downloads[0].last_used;
+        session.downloads[0].expires_at = Some(last_used);
+
+        assert_eq!(session.token_state(7), Some(TokenState::Expired));
+        session.record_usage(7, usage(UsageOutcome::Expired));
+        assert_eq!(session.downloads[0].last_used, last_used);
+    }
+
+    #[test]
+    fn live_downloads_are_limited() {
+        let mut session = Session::with_download(7);
+        let quota = DownloadQuota {
+            max_live: Some(1),
+            ..Default::default()
+        };
+
+        let refused = session.check_quota(&quota, 0).unwrap_err();
+        assert_eq!(refused.quota, QuotaKind::LiveDownloads);
+        assert_eq!(refused.limit, 1);
+
+        // Deleting the download frees its slot
+        session.delete_download(7);
+        assert!(session.check_quota(&quota, 0).is_ok());
+    }
+
+    #[test]
+    fn deleted_downloads_still_count_towards_the_hourly_limit() {
+        let mut session = Session::with_download(7);
+        session.downloads.clear();
+        let quota = DownloadQuota {
+            max_per_hour: Some(2),
+            ..Default::default()
+        };
+        let exe = executable(10);
+
+        for _ in 0..2 {
+            session.check_quota(&quota, 10).unwrap();
+            let token = session.add_download(&exe, None).token;
+            session.delete_download(token);
+        }
+
+        let refused = session.check_quota(&quota, 10).unwrap_err();
+        assert_eq!(refused.quota, QuotaKind::HourlyDownloads);
+        assert_eq!(refused.usage.last_hour, 2);
+    }
+
+    #[test]
+    fn served_bytes_are_limited() {
+        let mut session = Session::with_download(7);
+        let quota = DownloadQuota {
+            max_bytes: Some(15),
+            ..Default::default()
+        };
+        session.add_download(&executable(10), None);
+
+        assert_eq!(
+            session.check_quota(&quota, 10).unwrap_err().quota,
+            QuotaKind::Bytes
+        );
+        assert!(session.check_quota(&quota, 5).is_ok());
+    }
+
+    #[tokio::test]
+    async fn alerts_without_a_tab_are_delivered_to_the_next_one() {
+        let mut session = Session::with_download(7);
+        let quota = DownloadQuota::default();
+
+        assert_eq!(
+            session.send_alert(7, None, false).unwrap(),
+            UsageOutcome::Queued
+        );
+        assert_eq!(session.pending_alerts.len(), 1);
+
+        let queue = SendQueue::new(SendQueuePolicy::default(), Arc::default());
+        let connection_id = session.add_connection(queue.clone(), Protocol::legacy());
+        session.catch_up(connection_id, None, &quota).unwrap();
+        assert!(session.pending_alerts.is_empty());
+
+        queue.close();
+        let mut messages = Vec::new();
+        while let Some(message) = queue.recv().await {
+            messages.push(
+                serde_json::from_str::<serde_json::Value>(message.to_str().unwrap()).unwrap(),
+            );
+        }
+        assert_eq!(messages[0]["type"], "state");
+        assert_eq!(messages[1]["type"], "notify");
+        assert_eq!(messages[1]["token"], 7);
+        assert!(messages[1]["queued_at"].is_string());
+    }
+
+    fn event_types(events: &[serde_json::Value]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| event["type"].as_str().unwrap())
+            .collect()
+    }
+
+    fn published_download() -> (Session, u32) {
+        let token = rand::random();
+        let mut session = Session::with_download(token);
+        // No connections are open, so only the recorded events matter here
+        let _ = session.publish(
+            SessionChange::DownloadAdded(token),
+            &DownloadQuota::default(),
+        );
+        (session, token)
+    }
+
+    #[test]
+    fn replays_changes_after_last_seq() {
+        let quota = DownloadQuota::default();
+        let (mut session, token) = published_download();
+        let _ = session.publish(SessionChange::DownloadUpdated(token), &quota);
+
+        let events = session.events_since(0, &quota).unwrap();
+        assert_eq!(event_types(&events), ["download-added", "download-updated"]);
+        let events = session.events_since(1, &quota).unwrap();
+        assert_eq!(event_types(&events), ["download-updated"]);
+        assert!(session.events_since(2, &quota).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshot_is_replaced_by_state() {
+        let quota = DownloadQuota::default();
+        let (mut session, token) = published_download();
+        let _ = session.send_state(&quota);
+        session.send_alert(token, None, false).unwrap();
+
+        let events = session.events_since(0, &quota).unwrap();
+        assert_eq!(event_types(&events), ["state", "notify"]);
+    }
+
+    #[test]
+    fn evicted_events_are_replaced_by_state() {
+        let quota = DownloadQuota::default();
+        let (mut session, token) = published_download();
+        for _ in 0..MAX_BUFFERED_EVENTS {
+            let _ = session.publish(SessionChange::DownloadUpdated(token), &quota);
+        }
+
+        let events = session.events_since(0, &quota).unwrap();
+        assert_eq!(event_types(&events), ["state"]);
+        let events = session.events_since(session.seq - 1, &quota).unwrap();
+        assert_eq!(event_types(&events), ["download-updated"]);
+    }
+
+    #[test]
+    fn unknown_last_seq_gets_state() {
+        let quota = DownloadQuota::default();
+        let (session, _) = published_download();
+
+        for last_seq in [session.seq + 1, u64::MAX] {
+            let events = session.events_since(last_seq, &quota).unwrap();
+            assert_eq!(event_types(&events), ["state"]);
+        }
+    }
 }
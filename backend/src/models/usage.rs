@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{AppError, Result};
+
+/// Maximum accepted size of a `/notify` request body, in bytes.
+pub const MAX_CONTEXT_BODY_SIZE: usize = 4 * 1024;
+
+const MAX_HOSTNAME_LEN: usize = 255;
+const MAX_PLATFORM_LEN: usize = 32;
+const MAX_VERSION_LEN: usize = 64;
+const HASH_LEN: usize = 64;
+const MAX_MESSAGE_LEN: usize = 280;
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Number of usage events kept per download before the oldest are dropped.
+pub const MAX_USAGE_EVENTS: usize = 32;
+
+/// Context reported by a client binary when it uses its embedded token.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientContext {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    pub client_version: Option<String>,
+    // Hex-encoded SHA-256 of the working directory the binary was run from
+    pub cwd_hash: Option<String>,
+    // Hex-encoded SHA-256 identifying the machine, used for machine binding
+    pub machine_fingerprint: Option<String>,
+    // A free-form message supplied by the user running the binary
+    pub message: Option<String>,
+}
+
+impl ClientContext {
+    /// Checks every field against its length limit and character set.
+    pub fn validate(&self) -> Result<()> {
+        check_field("hostname", &self.hostname, MAX_HOSTNAME_LEN)?;
+        check_field("os", &self.os, MAX_PLATFORM_LEN)?;
+        check_field("arch", &self.arch, MAX_PLATFORM_LEN)?;
+        check_field("client_version", &self.client_version, MAX_VERSION_LEN)?;
+        check_field("message", &self.message, MAX_MESSAGE_LEN)?;
+
+        check_hash("cwd_hash", &self.cwd_hash)?;
+        check_hash("machine_fingerprint", &self.machine_fingerprint)?;
+
+        Ok(())
+    }
+}
+
+pub(super) fn check_field(name: &str, value: &Option<String>, max_len: usize) -> Result<()> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+
+    if value.chars().count() > max_len {
+        return Err(AppError::InvalidPayload {
+            message: format!("'{}' exceeds {} characters", name, max_len),
+        });
+    }
+
+    if value.chars().any(char::is_control) {
+        return Err(AppError::InvalidPayload {
+            message: format!("'{}' contains control characters", name),
+        });
+    }
+
+    Ok(())
+}
+
+/// What happened when a token was presented to `/notify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UsageOutcome {
+    // The alert reached at least one open tab
+    Delivered,
+    // The token was valid, but the session had no open tabs, so the alert was queued
+    Queued,
+    // The token was valid, but the alert could not be sent or queued
+    #[serde(alias = "no-socket")]
+    Failed,
+    // The token was deleted by the user
+    Revoked,
+    // The token outlived its configured lifetime
+    Expired,
+    // The token is bound to another machine and the binding is enforced
+    MachineMismatch,
+}
+
+fn check_hash(name: &str, value: &Option<String>) -> Result<()> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+
+    if value.len() != HASH_LEN || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(AppError::InvalidPayload {
+            message: format!("'{}' must be {} hex characters", name, HASH_LEN),
+        });
+    }
+
+    Ok(())
+}
+
+/// A single use of a download token, as recorded by `/notify`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageEvent {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub remote_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: UsageOutcome,
+    // True if the reported machine fingerprint differed from the bound one
+    pub machine_mismatch: bool,
+    pub context: Option<ClientContext>,
+}
+
+impl UsageEvent {
+    pub fn new(
+        outcome: UsageOutcome,
+        machine_mismatch: bool,
+        remote_ip: Option<String>,
+        user_agent: Option<&str>,
+        context: Option<ClientContext>,
+    ) -> Self {
+        Self {
+            timestamp: chrono::Utc::now(),
+            remote_ip,
+            user_agent: user_agent.map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect()),
+            outcome,
+            machine_mismatch,
+            context,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_within_limits_is_accepted() {
+        let context = ClientContext {
+            hostname: Some("h".repeat(MAX_HOSTNAME_LEN)),
+            os: Some("linux".to_string()),
+            cwd_hash: Some("a".repeat(HASH_LEN)),
+            message: Some("é".repeat(MAX_MESSAGE_LEN)),
+            ..Default::default()
+        };
+
+        assert!(context.validate().is_ok());
+    }
+
+    #[test]
+    fn oversized_fields_are_rejected() {
+        let hostname = ClientContext {
+            hostname: Some("h".repeat(MAX_HOSTNAME_LEN + 1)),
+            ..Default::default()
+        };
+        let message = ClientContext {
+            message: Some("m".repeat(MAX_MESSAGE_LEN + 1)),
+            ..Default::default()
+        };
+
+        assert!(hostname.validate().is_err());
+        assert!(message.validate().is_err());
+    }
+
+    #[test]
+    fn control_characters_and_bad_hashes_are_rejected() {
+        let message = ClientContext {
+            message: Some("line\nbreak".to_string()),
+            ..Default::default()
+        };
+        let cwd_hash = ClientContext {
+            cwd_hash: Some("z".repeat(HASH_LEN)),
+            ..Default::default()
+        };
+
+        assert!(message.validate().is_err());
+        assert!(cwd_hash.validate().is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let parsed = serde_json::from_str::<ClientContext>(r#"{"hostname":"h","extra":1}"#);
+
+        assert!(parsed.is_err());
+    }
+}
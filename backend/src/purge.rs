@@ -0,0 +1,43 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::state::State;
+
+/// Lifetimes after which sessions are evicted by the purge task.
+#[derive(Debug, Clone, Copy)]
+pub struct PurgePolicy {
+    /// How often a sweep runs.
+    pub interval: Duration,
+    /// Sessions not seen for this long are evicted.
+    pub idle_timeout: chrono::Duration,
+    /// Sessions older than this are evicted, regardless of activity.
+    pub max_lifetime: chrono::Duration,
+}
+
+/// Spawns a background task that periodically evicts stale sessions.
+pub fn spawn_session_purger(state: Arc<State>, policy: PurgePolicy) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(policy.interval);
+        // The first tick completes immediately; nothing is stale at startup
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let purged = state.purge_sessions(policy.idle_timeout, policy.max_lifetime);
+            let remaining = state.storage.session_count();
+
+            let metrics = &state.metrics;
+            metrics.purge_sweeps_total.fetch_add(1, Ordering::Relaxed);
+            metrics
+                .sessions_purged_total
+                .fetch_add(purged as u64, Ordering::Relaxed);
+            metrics
+                .sessions_active
+                .store(remaining as u64, Ordering::Relaxed);
+
+            tracing::info!(purged, remaining, "Session purge sweep complete");
+        }
+    });
+}
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use salvo::affix_state;
+use salvo::prelude::{CatchPanic, Router, StaticDir};
+
+use crate::handlers::{
+    connect, download, download_reserved, exchange_token, export_session, get_build_logs,
+    get_metrics, get_session, import_session, notify, poll_device_token, post_command,
+    request_device_code, session_middleware, stream_events, wait_for_events,
+};
+use crate::state::State;
+
+/// Builds the full route tree around a `State`.
+///
+/// Each router carries its own state, so independent servers can run side by side in one
+/// process.
+pub fn router(state: Arc<State>) -> Router {
+    let static_dir = StaticDir::new(["./public"]).defaults("index.html");
+
+    Router::new()
+        .hoop(CatchPanic::new())
+        .hoop(affix_state::inject(state))
+        // /notify does not need a session, nor should it have one
+        .push(Router::with_path("notify").post(notify))
+        // /token/exchange authenticates with the embedded token instead of a session
+        .push(Router::with_path("token/exchange").post(exchange_token))
+        // /device is used by binaries without a usable embedded token
+        .push(Router::with_path("device/code").post(request_device_code))
+        .push(Router::with_path("device/token").post(poll_device_token))
+        // /build-logs does not need a session
+        .push(Router::with_path("build-logs").get(get_build_logs))
+        .push(Router::with_path("metrics").get(get_metrics))
+        // Reserved downloads are fetched by URL alone, e.g. from another device
+        .push(Router::with_path("download/reserved/<id>").get(download_reserved))
+        .push(
+            Router::new()
+                .hoop(session_middleware)
+                .push(Router::with_path("download/<id>").get(download))
+                .push(Router::with_path("session").get(get_session))
+                .push(Router::with_path("session/wait").get(wait_for_events))
+                .push(Router::with_path("session/export").get(export_session))
+                .push(Router::with_path("session/import").post(import_session))
+                // websocket /ws
+                .push(Router::with_path("ws").goal(connect))
+                // Server-Sent Events, for clients whose proxies break WebSocket upgrades
+                .push(
+                    Router::with_path("events")
+                        .get(stream_events)
+                        .push(Router::with_path("<connection>").post(post_command)),
+                )
+                // static files
+                .push(Router::with_path("<**path>").get(static_dir)),
+        )
+}
@@ -0,0 +1,164 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a signature is for. The purpose is signed along with the data, so a value signed for
+/// one purpose is never accepted for another.
+#[derive(Debug, Clone, Copy)]
+pub enum Purpose {
+    SessionCookie,
+    SessionExport,
+}
+
+impl Purpose {
+    fn tag(self) -> &'static [u8] {
+        match self {
+            Purpose::SessionCookie => b"cookie:",
+            Purpose::SessionExport => b"export:",
+        }
+    }
+}
+
+/// HMAC keys used to sign values handed to clients, such as session cookies.
+///
+/// The first key signs; every key verifies. Rotating means prepending a new secret and
+/// keeping the old one around until everything signed with it has been re-signed.
+#[derive(Clone)]
+pub struct SigningKeys {
+    keys: Vec<Vec<u8>>,
+}
+
+impl SigningKeys {
+    /// Builds signing keys from secrets, newest first. Generates a random key if none are given.
+    pub fn new(secrets: Vec<String>) -> Self {
+        let mut keys: Vec<Vec<u8>> = secrets
+            .into_iter()
+            .filter(|secret| !secret.is_empty())
+            .map(String::into_bytes)
+            .collect();
+
+        if keys.is_empty() {
+            keys.push(rand::random::<[u8; 32]>().to_vec());
+        }
+
+        Self { keys }
+    }
+
+    /// Signs a value, producing `<value>.<signature>`.
+    pub fn sign(&self, purpose: Purpose, value: &str) -> String {
+        format!("{}.{}", value, self.signature(purpose, value.as_bytes()))
+    }
+
+    /// Verifies a value produced by `sign`, returning the original value.
+    /// The flag is true if it was signed with an older key and should be re-signed.
+    pub fn verify<'a>(&self, purpose: Purpose, signed: &'a str) -> Option<(&'a str, bool)> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        self.verify_signature(purpose, value.as_bytes(), signature)
+            .map(|outdated_key| (value, outdated_key))
+    }
+
+    /// Computes a hex-encoded signature over data, for when it travels apart from the data.
+    pub fn signature(&self, purpose: Purpose, data: &[u8]) -> String {
+        hex::encode(
+            Self::mac(&self.keys[0], purpose, data)
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    /// Verifies a signature produced by `signature`.
+    /// Returns true if it was signed with an older key, or `None` if it is invalid.
+    pub fn verify_signature(&self, purpose: Purpose, data: &[u8], signature: &str) -> Option<bool> {
+        let signature = hex::decode(signature).ok()?;
+
+        self.keys
+            .iter()
+            .position(|key| {
+                Self::mac(key, purpose, data)
+                    .verify_slice(&signature)
+                    .is_ok()
+            })
+            .map(|index| index > 0)
+    }
+
+    fn mac(key: &[u8], purpose: Purpose, data: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(purpose.tag());
+        mac.update(data);
+        mac
+    }
+}
+
+impl Default for SigningKeys {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl std::fmt::Debug for SigningKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKeys")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_value_round_trips() {
+        let keys = SigningKeys::new(vec!["secret".to_string()]);
+        let signed = keys.sign(Purpose::SessionCookie, "value.with.dots");
+
+        assert_eq!(
+            keys.verify(Purpose::SessionCookie, &signed),
+            Some(("value.with.dots", false))
+        );
+    }
+
+    #[test]
+    fn tampered_value_is_rejected() {
+        let keys = SigningKeys::new(vec!["secret".to_string()]);
+        let signed = keys.sign(Purpose::SessionCookie, "value");
+        let (_, signature) = signed.rsplit_once('.').unwrap();
+
+        assert_eq!(
+            keys.verify(Purpose::SessionCookie, &format!("other.{}", signature)),
+            None
+        );
+        assert_eq!(keys.verify(Purpose::SessionCookie, "value"), None);
+        assert_eq!(keys.verify(Purpose::SessionCookie, "value.not-hex"), None);
+    }
+
+    #[test]
+    fn signature_is_bound_to_its_purpose() {
+        let keys = SigningKeys::new(vec!["secret".to_string()]);
+        let signature = keys.signature(Purpose::SessionExport, b"data");
+
+        assert_eq!(
+            keys.verify_signature(Purpose::SessionExport, b"data", &signature),
+            Some(false)
+        );
+        assert_eq!(
+            keys.verify_signature(Purpose::SessionCookie, b"data", &signature),
+            None
+        );
+    }
+
+    #[test]
+    fn rotated_keys_still_verify_but_are_flagged() {
+        let old = SigningKeys::new(vec!["old".to_string()]);
+        let rotated = SigningKeys::new(vec!["new".to_string(), "old".to_string()]);
+        let retired = SigningKeys::new(vec!["new".to_string()]);
+        let signed = old.sign(Purpose::SessionCookie, "value");
+
+        assert_eq!(
+            rotated.verify(Purpose::SessionCookie, &signed),
+            Some(("value", true))
+        );
+        assert_eq!(retired.verify(Purpose::SessionCookie, &signed), None);
+    }
+}
@@ -1,34 +1,85 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::path::{Path, PathBuf};
-use std::sync::LazyLock;
+use std::sync::{Arc, Mutex};
 
-use salvo::{http::cookie::Cookie, Response};
-use tokio::sync::Mutex;
+use arc_swap::ArcSwap;
+use salvo::{http::cookie::Cookie, Depot, Response};
 
+use crate::config::MachineBinding;
 use crate::errors::{AppError, Result};
-use crate::models::{BuildLogs, Executable, ExecutableJson, Session};
+use crate::metrics::Metrics;
+use crate::models::{
+    BuildLogs, DownloadQuota, Executable, ExecutableJson, Heartbeat, PairingCodes, QuotaExceeded,
+    Reservations, SendQueuePolicy, Session, SessionExport, SessionId, TokenState,
+    DEFAULT_EXPORT_MAX_AGE,
+};
+use crate::signing::{Purpose, SigningKeys};
+use crate::storage::{MemoryStorage, SessionHandle, Storage};
 
-pub static STORE: LazyLock<Mutex<State>> = LazyLock::new(|| Mutex::new(State::new()));
-
-#[derive(Default)]
+/// Shared server state, injected into each request's `Depot` as an `Arc<State>`.
+///
+/// Sessions are locked individually and executables are swapped atomically, so there is no
+/// lock covering the whole of it.
 pub struct State {
-    pub sessions: HashMap<u32, Session>,
-    pub executables: HashMap<String, Executable>,
+    // Sessions and tokens, either in memory or persisted
+    pub storage: Box<dyn Storage>,
+    // Read on every download, written only at startup
+    pub executables: ArcSwap<HashMap<String, Arc<Executable>>>,
     pub build_logs: Option<BuildLogs>,
     pub build_log_url: Option<String>,
+    // How long a download token remains usable, if limited
+    pub token_lifetime: Option<chrono::Duration>,
+    pub machine_binding: MachineBinding,
+    // Limits on the downloads each session may create
+    pub download_quota: DownloadQuota,
+    // How WebSocket connections are checked for liveness
+    pub heartbeat: Heartbeat,
+    // Limits on the messages buffered for each WebSocket connection
+    pub send_queue: SendQueuePolicy,
+    // Counters and gauges reported by `/metrics`, shared with each connection's send queue
+    pub metrics: Arc<Metrics>,
+    // Keys used to sign session cookies
+    pub session_keys: SigningKeys,
+    // How many `X-Forwarded-For` entries, counted from the right, come from trusted proxies
+    pub trusted_proxy_hops: usize,
+    // How old a session export may be and still be imported
+    pub export_max_age: chrono::Duration,
+    // Codes for pairing other browsers into a session
+    pub pairing_codes: Mutex<PairingCodes>,
+    // Downloads reserved over the WebSocket, awaiting their one-time fetch
+    pub reservations: Mutex<Reservations>,
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
-            sessions: HashMap::new(),
-            executables: HashMap::new(),
+            storage: Box::new(MemoryStorage::default()),
+            executables: ArcSwap::default(),
             build_logs: None,
             build_log_url: None,
+            token_lifetime: None,
+            machine_binding: MachineBinding::Off,
+            download_quota: DownloadQuota::default(),
+            heartbeat: Heartbeat::default(),
+            send_queue: SendQueuePolicy::default(),
+            metrics: Arc::default(),
+            session_keys: SigningKeys::default(),
+            trusted_proxy_hops: 0,
+            export_max_age: DEFAULT_EXPORT_MAX_AGE,
+            pairing_codes: Mutex::default(),
+            reservations: Mutex::default(),
         }
     }
 
-    pub fn add_executable(&mut self, exe_type: &str, exe_path: &str) -> Result<()> {
+    /// The state injected into the depot by `affix_state::inject`.
+    pub fn from_depot(depot: &Depot) -> Arc<State> {
+        depot
+            .obtain::<Arc<State>>()
+            .expect("State was not injected into the depot")
+            .clone()
+    }
+
+    pub fn add_executable(&self, exe_type: &str, exe_path: &str) -> Result<()> {
         let path = Path::new(exe_path);
 
         let data = std::fs::read(path).map_err(|_| AppError::ExecutableNotFound {
@@ -65,51 +116,211 @@ impl State {
             key_end,
         };
 
-        self.executables.insert(exe_type.to_string(), exe);
+        let exe = Arc::new(exe);
+        self.executables.rcu(|executables| {
+            let mut executables = HashMap::clone(executables);
+            executables.insert(exe_type.to_string(), exe.clone());
+            executables
+        });
         Ok(())
     }
 
-    pub async fn new_session(&mut self, res: &mut Response) -> u32 {
-        let id: u32 = rand::random();
+    pub fn new_session(&self, res: &mut Response) -> SessionId {
+        let id = SessionId::random();
 
-        let now = chrono::Utc::now();
-        self.sessions.insert(
-            id,
-            Session {
-                id,
-                downloads: Vec::new(),
-                last_seen: now,
-                last_request: now,
-                first_seen: now,
-                connections: HashMap::new(),
-            },
-        );
+        self.storage.insert_session(Session::new(id));
 
         tracing::info!("New session created: {}", id);
 
+        self.set_session_cookie(res, id);
+        id
+    }
+
+    /// Set the signed `Session` cookie for a session ID.
+    pub fn set_session_cookie(&self, res: &mut Response, id: SessionId) {
         res.add_cookie(
-            Cookie::build(("Session", id.to_string()))
-                .http_only(true)
-                .partitioned(true)
-                .secure(cfg!(debug_assertions) == false)
-                .path("/")
-                // Use SameSite=None only in development
-                .same_site(if cfg!(debug_assertions) {
-                    salvo::http::cookie::SameSite::None
-                } else {
-                    salvo::http::cookie::SameSite::Strict
-                })
-                .permanent()
-                .build(),
+            Cookie::build((
+                "Session",
+                self.session_keys
+                    .sign(Purpose::SessionCookie, &id.to_string()),
+            ))
+            .http_only(true)
+            .partitioned(true)
+            .secure(cfg!(debug_assertions) == false)
+            .path("/")
+            // Use SameSite=None only in development
+            .same_site(if cfg!(debug_assertions) {
+                salvo::http::cookie::SameSite::None
+            } else {
+                salvo::http::cookie::SameSite::Strict
+            })
+            .permanent()
+            .build(),
         );
+    }
 
-        id
+    /// Evict sessions that have been idle or alive for too long, along with their credentials.
+    /// Sessions with open WebSocket connections are always kept. Returns the number evicted.
+    pub fn purge_sessions(
+        &self,
+        idle_timeout: chrono::Duration,
+        max_lifetime: chrono::Duration,
+    ) -> usize {
+        let now = chrono::Utc::now();
+        let mut purged = 0;
+
+        for handle in self.storage.sessions() {
+            // Hold the session while removing it, so a connection can't open in between
+            let session = handle.lock();
+            let stale =
+                now - session.last_seen > idle_timeout || now - session.first_seen > max_lifetime;
+            if !stale || !session.connections.is_empty() {
+                continue;
+            }
+
+            self.storage.remove_session(session.id);
+            self.storage.revoke_credentials(session.id);
+            tracing::debug!(session_id = %session.id, "Session purged");
+            purged += 1;
+        }
+
+        purged
+    }
+
+    /// Pair session `from` into session `into`, so both cookies share one session from now on.
+    /// Returns the merged session, or `None` if either is missing or they are already paired.
+    pub fn pair_sessions(&self, from: SessionId, into: SessionId) -> Option<SessionHandle> {
+        let target = self.storage.session(into)?;
+        let source = self.storage.session(from)?;
+        if Arc::ptr_eq(&source, &target) {
+            return None;
+        }
+
+        let from = source.lock().id;
+        let into = target.lock().id;
+
+        // Once aliased, requests for `from` resolve to the target, so nothing new lands in the
+        // source while its contents are moved over
+        self.storage.alias_session(from, into)?;
+        let source = std::mem::replace(&mut *source.lock(), Session::new(from));
+
+        for download in source.downloads.iter().chain(source.revoked.iter()) {
+            self.storage.index_token(download.token, into);
+        }
+        self.storage.reassign_credentials(from, into);
+
+        target.lock().merge(source);
+        tracing::info!(session_id = %into, paired_session_id = %from, "Sessions paired");
+        Some(target)
+    }
+
+    /// Restore the downloads in an export into a session, taking them away from the exporting
+    /// session (or whichever session it was paired into) if it still holds them. Tokens held
+    /// by any other session are left where they are. Returns the number restored, an error if
+    /// the live downloads would exceed the session's quota, or `None` if the session is missing.
+    pub fn import_session(
+        &self,
+        into: SessionId,
+        export: SessionExport,
+    ) -> Option<std::result::Result<usize, QuotaExceeded>> {
+        let target = self.storage.session(into)?;
+        let source = self
+            .storage
+            .session(export.session_id)
+            .filter(|source| !Arc::ptr_eq(source, &target));
+
+        // Both sessions stay locked until the downloads have moved, so neither can change
+        // underneath the quota check. They are locked in address order, so two imports
+        // running in opposite directions can't deadlock.
+        let (mut target_guard, mut source_guard) = match &source {
+            Some(source) if Arc::as_ptr(source) < Arc::as_ptr(&target) => {
+                let source = source.lock();
+                (target.lock(), Some(source))
+            }
+            Some(source) => {
+                let target = target.lock();
+                (target, Some(source.lock()))
+            }
+            None => (target.lock(), None),
+        };
+        let into = target_guard.id;
+
+        let mut downloads = Vec::new();
+        let mut revoked = VecDeque::new();
+        let mut taken = Vec::new();
+        let exported = export
+            .downloads
+            .into_iter()
+            .map(|download| (download, false))
+            .chain(export.revoked.into_iter().map(|download| (download, true)));
+        for (download, was_revoked) in exported {
+            let token = download.token;
+            let mut is_revoked = was_revoked;
+            match self.storage.session_for_token(token) {
+                None => {}
+                Some(holder) if Arc::ptr_eq(&holder, &target) => {}
+                Some(holder) if source.as_ref().is_some_and(|s| Arc::ptr_eq(&holder, s)) => {
+                    // Revocation is final: a download revoked since the export was taken
+                    // comes back revoked
+                    let source = source_guard.as_mut().unwrap();
+                    is_revoked |= source.token_state(token) == Some(TokenState::Revoked);
+                    taken.push(token);
+                }
+                Some(_) => {
+                    tracing::warn!(session_id = %into, dl_token = token, "Not importing a download held by another session");
+                    continue;
+                }
+            }
+
+            if is_revoked {
+                revoked.push_back(download);
+            } else {
+                downloads.push(download);
+            }
+        }
+
+        let incoming = downloads
+            .iter()
+            .filter(|download| {
+                target_guard.token_state(download.token).is_none() && download.is_live()
+            })
+            .count();
+        if incoming > 0 {
+            if let Err(exceeded) = target_guard.check_quota_for(&self.download_quota, incoming, 0) {
+                return Some(Err(exceeded));
+            }
+        }
+
+        if let Some(source) = source_guard.as_mut() {
+            for token in &taken {
+                source.remove_download(*token);
+            }
+        }
+        let tokens: Vec<u32> = downloads
+            .iter()
+            .chain(revoked.iter())
+            .map(|download| download.token)
+            .collect();
+        let restored = target_guard.import(downloads, revoked);
+        for token in tokens {
+            self.storage.index_token(token, into);
+        }
+
+        tracing::info!(session_id = %into, exported_from = %export.session_id, restored, "Session imported");
+        Some(Ok(restored))
+    }
+
+    /// Find the session owning a download token, along with the token's state.
+    pub fn session_for_token(&self, token: u32) -> Option<(SessionHandle, TokenState)> {
+        let handle = self.storage.session_for_token(token)?;
+        let state = handle.lock().token_state(token)?;
+        Some((handle, state))
     }
 
     pub fn executable_json(&self) -> Vec<ExecutableJson> {
         let mut executables = Vec::new();
 
-        for (key, exe) in &self.executables {
+        for (key, exe) in self.executables.load().iter() {
             executables.push(ExecutableJson {
                 id: key.to_string(),
                 size: exe.data.len(),
@@ -120,3 +331,123 @@ impl State {
         executables
     }
 }
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FakeStorage;
+
+    fn state_with_fake_storage() -> (State, Arc<Mutex<Vec<String>>>) {
+        let storage = FakeStorage::default();
+        let calls = storage.calls.clone();
+        let state = State {
+            storage: Box::new(storage),
+            ..State::new()
+        };
+        (state, calls)
+    }
+
+    #[test]
+    fn purging_a_session_revokes_its_credentials() {
+        let (state, calls) = state_with_fake_storage();
+        let mut stale = Session::new(SessionId::random());
+        stale.last_seen = chrono::Utc::now() - chrono::Duration::days(2);
+        let stale_id = stale.id;
+        state.storage.insert_session(stale);
+        let fresh_id = SessionId::random();
+        state.storage.insert_session(Session::new(fresh_id));
+
+        let purged = state.purge_sessions(chrono::Duration::days(1), chrono::Duration::days(7));
+
+        assert_eq!(purged, 1);
+        assert!(state.storage.session(stale_id).is_none());
+        assert!(state.storage.session(fresh_id).is_some());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [format!("revoke_credentials {}", stale_id)]
+        );
+    }
+
+    #[test]
+    fn pairing_moves_downloads_and_credentials() {
+        let (state, calls) = state_with_fake_storage();
+        let into = SessionId::random();
+        let token = rand::random();
+        let source = Session::with_download(token);
+        let from = source.id;
+        state.storage.insert_session(source);
+        state.storage.insert_session(Session::new(into));
+
+        let paired = state.pair_sessions(from, into).unwrap();
+
+        assert_eq!(paired.lock().id, into);
+        assert!(Arc::ptr_eq(&state.storage.session(from).unwrap(), &paired));
+        let (holder, token_state) = state.session_for_token(token).unwrap();
+        assert!(Arc::ptr_eq(&holder, &paired));
+        assert_eq!(token_state, TokenState::Active);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [format!("reassign_credentials {} {}", from, into)]
+        );
+    }
+
+    // A session holding one download, and an export of it
+    fn exported_session(state: &State) -> (SessionHandle, u32, SessionExport) {
+        let token = rand::random();
+        let session = Session::with_download(token);
+        let export = SessionExport::new(&session);
+        (state.storage.insert_session(session), token, export)
+    }
+
+    #[test]
+    fn importing_takes_downloads_from_the_exporting_session() {
+        let state = State::new();
+        let (exporter, token, export) = exported_session(&state);
+        let into = SessionId::random();
+        let target = state.storage.insert_session(Session::new(into));
+
+        assert_eq!(state.import_session(into, export).unwrap().unwrap(), 1);
+        assert_eq!(exporter.lock().token_state(token), None);
+        let (holder, token_state) = state.session_for_token(token).unwrap();
+        assert!(Arc::ptr_eq(&holder, &target));
+        assert_eq!(token_state, TokenState::Active);
+    }
+
+    #[test]
+    fn importing_leaves_other_sessions_downloads_alone() {
+        let state = State::new();
+        let (holder, token, _) = exported_session(&state);
+
+        // An export from an unrelated session that claims the same token
+        let mut forged = Session::new(SessionId::random());
+        forged.downloads = holder.lock().downloads.clone();
+        let export = SessionExport::new(&forged);
+        let into = SessionId::random();
+        state.storage.insert_session(Session::new(into));
+
+        assert_eq!(state.import_session(into, export).unwrap().unwrap(), 0);
+        let (owner, token_state) = state.session_for_token(token).unwrap();
+        assert!(Arc::ptr_eq(&owner, &holder));
+        assert_eq!(token_state, TokenState::Active);
+    }
+
+    #[test]
+    fn refused_imports_move_nothing() {
+        let mut state = State::new();
+        state.download_quota.max_live = Some(0);
+        let (exporter, token, export) = exported_session(&state);
+        let into = SessionId::random();
+        state.storage.insert_session(Session::new(into));
+
+        assert!(state.import_session(into, export).unwrap().is_err());
+        let (holder, token_state) = state.session_for_token(token).unwrap();
+        assert!(Arc::ptr_eq(&holder, &exporter));
+        assert_eq!(token_state, TokenState::Active);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::errors::Result;
+use crate::models::{DevicePoll, Grant, Session, SessionId, TokenResponse};
+
+use super::{lock, SessionHandle, SessionStore, SharedSession, Storage, TokenStore};
+
+/// A storage backend for tests, sharing nothing with `MemoryStorage`.
+///
+/// Sessions are kept in plain maps, while token operations are only recorded, so tests can
+/// check what `State` asked of its storage.
+#[derive(Debug, Default)]
+pub struct FakeStorage {
+    sessions: Mutex<HashMap<SessionId, SessionHandle>>,
+    aliases: Mutex<HashMap<SessionId, SessionId>>,
+    tokens: Mutex<HashMap<u32, SessionId>>,
+    // Each token operation, e.g. `revoke_credentials <session id>`
+    pub calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeStorage {
+    fn record(&self, call: String) {
+        lock(&self.calls).push(call);
+    }
+}
+
+impl SessionStore for FakeStorage {
+    fn session(&self, id: SessionId) -> Option<SessionHandle> {
+        let id = lock(&self.aliases).get(&id).copied().unwrap_or(id);
+        lock(&self.sessions).get(&id).cloned()
+    }
+
+    fn insert_session(&self, session: Session) -> SessionHandle {
+        let id = session.id;
+        for download in session.downloads.iter().chain(session.revoked.iter()) {
+            self.index_token(download.token, id);
+        }
+
+        let handle = Arc::new(SharedSession::new(session));
+        lock(&self.sessions).insert(id, handle.clone());
+        handle
+    }
+
+    fn remove_session(&self, id: SessionId) -> Option<SessionHandle> {
+        lock(&self.tokens).retain(|_, session_id| *session_id != id);
+        lock(&self.aliases).retain(|_, session_id| *session_id != id);
+        lock(&self.sessions).remove(&id)
+    }
+
+    fn alias_session(&self, alias: SessionId, target: SessionId) -> Option<SessionHandle> {
+        let mut sessions = lock(&self.sessions);
+        if !sessions.contains_key(&target) {
+            return None;
+        }
+
+        let mut aliases = lock(&self.aliases);
+        for session_id in aliases.values_mut() {
+            if *session_id == alias {
+                *session_id = target;
+            }
+        }
+        aliases.insert(alias, target);
+        sessions.remove(&alias)
+    }
+
+    fn sessions(&self) -> Vec<SessionHandle> {
+        lock(&self.sessions).values().cloned().collect()
+    }
+
+    fn session_count(&self) -> usize {
+        lock(&self.sessions).len()
+    }
+
+    fn index_token(&self, token: u32, id: SessionId) {
+        lock(&self.tokens).insert(token, id);
+    }
+
+    fn session_for_token(&self, token: u32) -> Option<SessionHandle> {
+        let id = *lock(&self.tokens).get(&token)?;
+        self.session(id)
+    }
+}
+
+impl TokenStore for FakeStorage {
+    fn issue_credentials(&self, session_id: SessionId, download_token: u32) -> TokenResponse {
+        self.record(format!(
+            "issue_credentials {} {}",
+            session_id, download_token
+        ));
+        TokenResponse {
+            access_token: "access".to_string(),
+            token_type: "Bearer",
+            expires_in: 0,
+            refresh_token: "refresh".to_string(),
+            refresh_expires_in: 0,
+        }
+    }
+
+    fn access_grant(&self, access_token: &str) -> Option<Grant> {
+        self.record(format!("access_grant {}", access_token));
+        None
+    }
+
+    fn take_refresh_grant(&self, refresh_token: &str) -> Option<Grant> {
+        self.record(format!("take_refresh_grant {}", refresh_token));
+        None
+    }
+
+    fn revoke_credentials(&self, session_id: SessionId) {
+        self.record(format!("revoke_credentials {}", session_id));
+    }
+
+    fn reassign_credentials(&self, from: SessionId, to: SessionId) {
+        self.record(format!("reassign_credentials {} {}", from, to));
+    }
+
+    fn start_device_authorization(&self) -> (String, String) {
+        self.record("start_device_authorization".to_string());
+        ("device".to_string(), "BCDF-GHJK".to_string())
+    }
+
+    fn approve_device_code(&self, user_code: &str, token: u32) -> bool {
+        self.record(format!("approve_device_code {} {}", user_code, token));
+        false
+    }
+
+    fn poll_device_code(&self, device_code: &str) -> DevicePoll {
+        self.record(format!("poll_device_code {}", device_code));
+        DevicePoll::Expired
+    }
+}
+
+impl Storage for FakeStorage {
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
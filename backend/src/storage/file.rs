@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::errors::{AppError, Result};
+use crate::models::{
+    Credentials, DeviceAuthorizations, DevicePoll, Grant, Session, SessionId, TokenResponse,
+};
+
+use super::memory::MemoryStorage;
+use super::{SessionHandle, SessionStore, Storage, TokenStore};
+
+/// Bumped whenever the snapshot layout changes incompatibly.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Everything in `State` that can't be rebuilt at startup.
+///
+/// Executables and build logs are re-read on boot, and WebSocket connections can't outlive
+/// the process, so neither is stored.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub saved_at: chrono::DateTime<chrono::Utc>,
+    pub sessions: Vec<Session>,
+    pub credentials: Credentials,
+    pub device_authorizations: DeviceAuthorizations,
+}
+
+/// Keeps everything in memory, snapshotting it to a JSON file on every flush.
+#[derive(Debug)]
+pub struct FileStorage {
+    memory: MemoryStorage,
+    path: PathBuf,
+}
+
+impl FileStorage {
+    /// Opens the snapshot at `path`, starting empty if it doesn't exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let memory = match Self::load(&path)? {
+            Some(snapshot) => {
+                tracing::info!(
+                    sessions = snapshot.sessions.len(),
+                    saved_at = %snapshot.saved_at,
+                    "Restored state from {}",
+                    path.display()
+                );
+                let memory =
+                    MemoryStorage::new(snapshot.credentials, snapshot.device_authorizations);
+                for session in snapshot.sessions {
+                    memory.insert_session(session);
+                }
+                memory
+            }
+            None => {
+                tracing::info!("No state found at {}, starting fresh", path.display());
+                MemoryStorage::default()
+            }
+        };
+
+        Ok(Self { memory, path })
+    }
+
+    fn load(path: &Path) -> Result<Option<Snapshot>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(persistence_error(path, e)),
+        };
+
+        let snapshot: Snapshot =
+            serde_json::from_slice(&data).map_err(|e| persistence_error(path, e))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(AppError::Persistence {
+                path: path.to_path_buf(),
+                message: format!(
+                    "snapshot version {} is not supported (expected {})",
+                    snapshot.version, SNAPSHOT_VERSION
+                ),
+            });
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            saved_at: chrono::Utc::now(),
+            sessions: self
+                .memory
+                .sessions()
+                .iter()
+                .map(|session| session.lock().clone())
+                .collect(),
+            credentials: self.memory.credentials().clone(),
+            device_authorizations: self.memory.device_authorizations().clone(),
+        }
+    }
+}
+
+impl SessionStore for FileStorage {
+    fn session(&self, id: SessionId) -> Option<SessionHandle> {
+        self.memory.session(id)
+    }
+
+    fn insert_session(&self, session: Session) -> SessionHandle {
+        self.memory.insert_session(session)
+    }
+
+    fn remove_session(&self, id: SessionId) -> Option<SessionHandle> {
+        self.memory.remove_session(id)
+    }
+
+    fn alias_session(&self, alias: SessionId, target: SessionId) -> Option<SessionHandle> {
+        self.memory.alias_session(alias, target)
+    }
+
+    fn sessions(&self) -> Vec<SessionHandle> {
+        self.memory.sessions()
+    }
+
+    fn session_count(&self) -> usize {
+        self.memory.session_count()
+    }
+
+    fn index_token(&self, token: u32, id: SessionId) {
+        self.memory.index_token(token, id)
+    }
+
+    fn session_for_token(&self, token: u32) -> Option<SessionHandle> {
+        self.memory.session_for_token(token)
+    }
+}
+
+impl TokenStore for FileStorage {
+    fn issue_credentials(&self, session_id: SessionId, download_token: u32) -> TokenResponse {
+        self.memory.issue_credentials(session_id, download_token)
+    }
+
+    fn access_grant(&self, access_token: &str) -> Option<Grant> {
+        self.memory.access_grant(access_token)
+    }
+
+    fn take_refresh_grant(&self, refresh_token: &str) -> Option<Grant> {
+        self.memory.take_refresh_grant(refresh_token)
+    }
+
+    fn revoke_credentials(&self, session_id: SessionId) {
+        self.memory.revoke_credentials(session_id)
+    }
+
+    fn reassign_credentials(&self, from: SessionId, to: SessionId) {
+        self.memory.reassign_credentials(from, to)
+    }
+
+    fn start_device_authorization(&self) -> (String, String) {
+        self.memory.start_device_authorization()
+    }
+
+    fn approve_device_code(&self, user_code: &str, token: u32) -> bool {
+        self.memory.approve_device_code(user_code, token)
+    }
+
+    fn poll_device_code(&self, device_code: &str) -> DevicePoll {
+        self.memory.poll_device_code(device_code)
+    }
+}
+
+impl Storage for FileStorage {
+    fn flush(&self) -> Result<()> {
+        let data =
+            serde_json::to_vec(&self.snapshot()).map_err(|e| persistence_error(&self.path, e))?;
+
+        // Write beside the target and rename over it, so a crash never leaves a partial snapshot
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, &data).map_err(|e| persistence_error(&self.path, e))?;
+        std::fs::rename(&temp_path, &self.path).map_err(|e| persistence_error(&self.path, e))?;
+
+        tracing::debug!(path = %self.path.display(), bytes = data.len(), "State snapshot saved");
+        Ok(())
+    }
+}
+
+fn persistence_error(path: &Path, error: impl std::fmt::Display) -> AppError {
+    AppError::Persistence {
+        path: path.to_path_buf(),
+        message: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("state-{}.json", SessionId::random()));
+        let token = rand::random();
+        let session = Session::with_download(token);
+        let session_id = session.id;
+
+        let storage = FileStorage::open(&path).unwrap();
+        storage.insert_session(session);
+        let issued = storage.issue_credentials(session_id, token);
+        storage.flush().unwrap();
+
+        let reopened = FileStorage::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(reopened.session(session_id).is_some());
+        assert_eq!(
+            reopened.session_for_token(token).unwrap().lock().id,
+            session_id
+        );
+        let grant = reopened.access_grant(&issued.access_token).unwrap();
+        assert_eq!(grant.download_token, token);
+        assert!(reopened.take_refresh_grant(&issued.refresh_token).is_some());
+    }
+}
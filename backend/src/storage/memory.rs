@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use dashmap::DashMap;
+
+use crate::errors::Result;
+use crate::models::{
+    Credentials, DeviceAuthorizations, DevicePoll, Grant, Session, SessionId, TokenResponse,
+};
+
+use super::{lock, SessionHandle, SessionStore, SharedSession, Storage, TokenStore};
+
+/// Keeps everything in memory; all data is lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    sessions: DashMap<SessionId, SessionHandle>,
+    // Paired session IDs, resolved to the session they were merged into
+    aliases: DashMap<SessionId, SessionId>,
+    tokens: DashMap<u32, SessionId>,
+    credentials: Mutex<Credentials>,
+    device_authorizations: Mutex<DeviceAuthorizations>,
+}
+
+impl MemoryStorage {
+    pub fn new(credentials: Credentials, device_authorizations: DeviceAuthorizations) -> Self {
+        Self {
+            credentials: Mutex::new(credentials),
+            device_authorizations: Mutex::new(device_authorizations),
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn credentials(&self) -> MutexGuard<'_, Credentials> {
+        lock(&self.credentials)
+    }
+
+    pub(super) fn device_authorizations(&self) -> MutexGuard<'_, DeviceAuthorizations> {
+        lock(&self.device_authorizations)
+    }
+}
+
+impl SessionStore for MemoryStorage {
+    fn session(&self, id: SessionId) -> Option<SessionHandle> {
+        let id = self.aliases.get(&id).map_or(id, |target| *target);
+        self.sessions.get(&id).map(|entry| entry.value().clone())
+    }
+
+    fn insert_session(&self, session: Session) -> SessionHandle {
+        let id = session.id;
+        for download in session.downloads.iter().chain(session.revoked.iter()) {
+            self.tokens.insert(download.token, id);
+        }
+        for alias in &session.aliases {
+            self.aliases.insert(*alias, id);
+        }
+
+        let handle = Arc::new(SharedSession::new(session));
+        self.sessions.insert(id, handle.clone());
+        handle
+    }
+
+    fn remove_session(&self, id: SessionId) -> Option<SessionHandle> {
+        let (_, handle) = self.sessions.remove(&id)?;
+        self.tokens.retain(|_, session_id| *session_id != id);
+        self.aliases.retain(|_, session_id| *session_id != id);
+        Some(handle)
+    }
+
+    fn alias_session(&self, alias: SessionId, target: SessionId) -> Option<SessionHandle> {
+        if !self.sessions.contains_key(&target) {
+            return None;
+        }
+
+        self.aliases.insert(alias, target);
+        for mut entry in self.aliases.iter_mut() {
+            if *entry.value() == alias {
+                *entry.value_mut() = target;
+            }
+        }
+
+        let (_, handle) = self.sessions.remove(&alias)?;
+        Some(handle)
+    }
+
+    fn sessions(&self) -> Vec<SessionHandle> {
+        self.sessions
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn index_token(&self, token: u32, id: SessionId) {
+        self.tokens.insert(token, id);
+    }
+
+    fn session_for_token(&self, token: u32) -> Option<SessionHandle> {
+        let id = *self.tokens.get(&token)?;
+        self.session(id)
+    }
+}
+
+impl TokenStore for MemoryStorage {
+    fn issue_credentials(&self, session_id: SessionId, download_token: u32) -> TokenResponse {
+        self.credentials().issue(session_id, download_token)
+    }
+
+    fn access_grant(&self, access_token: &str) -> Option<Grant> {
+        self.credentials().access_grant(access_token)
+    }
+
+    fn take_refresh_grant(&self, refresh_token: &str) -> Option<Grant> {
+        self.credentials().take_refresh_grant(refresh_token)
+    }
+
+    fn revoke_credentials(&self, session_id: SessionId) {
+        self.credentials().revoke_session(session_id)
+    }
+
+    fn reassign_credentials(&self, from: SessionId, to: SessionId) {
+        self.credentials().reassign_session(from, to)
+    }
+
+    fn start_device_authorization(&self) -> (String, String) {
+        self.device_authorizations().start()
+    }
+
+    fn approve_device_code(&self, user_code: &str, token: u32) -> bool {
+        self.device_authorizations()
+            .approve_pending(user_code, token)
+    }
+
+    fn poll_device_code(&self, device_code: &str) -> DevicePoll {
+        self.device_authorizations().poll(device_code)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Storage for sessions and tokens, abstracted so deployments can choose between keeping
+//! everything in memory and persisting it across restarts.
+
+#[cfg(test)]
+mod fake;
+mod file;
+mod memory;
+
+#[cfg(test)]
+pub use fake::FakeStorage;
+pub use file::{FileStorage, Snapshot};
+pub use memory::MemoryStorage;
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::errors::Result;
+use crate::models::{DevicePoll, Grant, Session, SessionId, TokenResponse};
+use crate::state::State;
+
+/// A session behind its own lock, so requests for different sessions never contend.
+///
+/// The lock is synchronous: it must never be held across an `.await`.
+#[derive(Debug)]
+pub struct SharedSession(Mutex<Session>);
+
+impl SharedSession {
+    pub fn new(session: Session) -> Self {
+        Self(Mutex::new(session))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Session> {
+        lock(&self.0)
+    }
+}
+
+pub type SessionHandle = Arc<SharedSession>;
+
+/// Holds every session, keyed by ID, along with indexes from paired session IDs and download
+/// tokens to the session they belong to.
+///
+/// Sessions are handed out as live handles, since they carry open connections and are locked
+/// individually. A durable backend keeps the handles it has loaded as a working set, and
+/// writes them out on `Storage::flush`.
+pub trait SessionStore: Send + Sync {
+    fn session(&self, id: SessionId) -> Option<SessionHandle>;
+    /// Inserts a session, indexing any download tokens it already holds.
+    fn insert_session(&self, session: Session) -> SessionHandle;
+    /// Removes a session and every download token and alias indexed for it.
+    fn remove_session(&self, id: SessionId) -> Option<SessionHandle>;
+    /// Removes session `alias`, resolving its ID and any of its own aliases to `target` from
+    /// now on. Returns the removed session so its contents can be merged into the target.
+    fn alias_session(&self, alias: SessionId, target: SessionId) -> Option<SessionHandle>;
+    fn sessions(&self) -> Vec<SessionHandle>;
+    fn session_count(&self) -> usize;
+    /// Records which session a newly created download token belongs to.
+    fn index_token(&self, token: u32, id: SessionId);
+    fn session_for_token(&self, token: u32) -> Option<SessionHandle>;
+}
+
+/// Holds credentials derived from download tokens.
+///
+/// Each method is a complete operation, so a backend can carry it out as a single transaction.
+pub trait TokenStore: Send + Sync {
+    /// Issues a new access & refresh token pair for a download token.
+    fn issue_credentials(&self, session_id: SessionId, download_token: u32) -> TokenResponse;
+    /// Looks up the grant behind an access token, if it exists and has not expired.
+    fn access_grant(&self, access_token: &str) -> Option<Grant>;
+    /// Consumes a refresh token, returning its grant if it was still valid.
+    fn take_refresh_grant(&self, refresh_token: &str) -> Option<Grant>;
+    /// Drops every credential issued for a session.
+    fn revoke_credentials(&self, session_id: SessionId);
+    /// Moves every credential issued for one session over to another.
+    fn reassign_credentials(&self, from: SessionId, to: SessionId);
+
+    /// Starts a device authorization, returning its device & user codes.
+    fn start_device_authorization(&self) -> (String, String);
+    /// Approves the device waiting on a user code with a download token. Returns false if no
+    /// device is waiting on it, including one that was already approved.
+    fn approve_device_code(&self, user_code: &str, token: u32) -> bool;
+    /// Polls a device code, consuming it once its token has been handed out.
+    fn poll_device_code(&self, device_code: &str) -> DevicePoll;
+}
+
+/// A complete storage backend for `State`.
+pub trait Storage: SessionStore + TokenStore {
+    /// Writes all changes to durable storage. A no-op for in-memory storage.
+    fn flush(&self) -> Result<()>;
+}
+
+/// Spawns a background task that flushes storage at a fixed interval.
+pub fn spawn_flusher(state: Arc<State>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = flush(state.clone()).await {
+                tracing::error!("{}", e);
+            }
+        }
+    });
+}
+
+/// Flushes storage on the blocking pool, since durable backends do file I/O.
+pub async fn flush(state: Arc<State>) -> Result<()> {
+    tokio::task::spawn_blocking(move || state.storage.flush())
+        .await
+        .expect("Storage flush panicked")
+}
+
+// A panic while holding one of these locks can't leave the data half-updated in a way
+// that matters more than losing the whole server, so poisoning is ignored
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
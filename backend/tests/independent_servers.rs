@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use dynamic_preauth::models::SendQueue;
+use dynamic_preauth::routes::router;
+use dynamic_preauth::signing::SigningKeys;
+use dynamic_preauth::state::State;
+use salvo::test::{ResponseExt, TestClient};
+use salvo::websocket::Message;
+use salvo::Service;
+
+fn server(secret: &str) -> (Arc<State>, Service) {
+    let mut state = State::new();
+    state.session_keys = SigningKeys::new(vec![secret.to_string()]);
+    let state = Arc::new(state);
+    (state.clone(), Service::new(router(state)))
+}
+
+async fn metric(service: &Service, name: &str) -> u64 {
+    let body = TestClient::get("http://127.0.0.1/metrics")
+        .send(service)
+        .await
+        .take_string()
+        .await
+        .unwrap();
+    let prefix = format!("dynamic_preauth_{} ", name);
+    body.lines()
+        .find_map(|line| line.strip_prefix(prefix.as_str()))
+        .and_then(|value| value.parse().ok())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn servers_keep_their_own_sessions() {
+    let (first_state, first) = server("first");
+    let (second_state, second) = server("second");
+
+    let response = TestClient::get("http://127.0.0.1/session")
+        .send(&first)
+        .await;
+    let cookie = response.cookie("Session").unwrap().to_string();
+
+    // The other server neither recognises the cookie nor shares the session it names
+    let response = TestClient::get("http://127.0.0.1/session")
+        .add_header("Cookie", cookie, true)
+        .send(&second)
+        .await;
+    assert!(response.cookie("Session").is_some());
+    assert_eq!(first_state.storage.session_count(), 1);
+    assert_eq!(second_state.storage.session_count(), 1);
+}
+
+#[tokio::test]
+async fn servers_report_their_own_metrics() {
+    let (first_state, first) = server("first");
+    let (_, second) = server("second");
+
+    let queue = SendQueue::new(first_state.send_queue, first_state.metrics.clone());
+    queue.send(Message::text("queued")).unwrap();
+
+    assert_eq!(metric(&first, "ws_send_queue_depth").await, 1);
+    assert_eq!(metric(&second, "ws_send_queue_depth").await, 0);
+
+    drop(queue);
+    assert_eq!(metric(&first, "ws_send_queue_depth").await, 0);
+}
@@ -9,6 +9,62 @@ struct KeyData<'a> {
     compile_time: String,
 }
 
+// Context sent alongside the token when notifying the server
+#[derive(Serialize, Debug)]
+struct ClientContext {
+    hostname: Option<String>,
+    os: String,
+    arch: String,
+    client_version: String,
+    cwd_hash: Option<String>,
+    machine_fingerprint: String,
+    message: Option<String>,
+}
+
+impl ClientContext {
+    fn collect(message: Option<String>) -> Self {
+        let hostname = std::env::var("COMPUTERNAME")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .ok()
+            .or_else(|| {
+                std::fs::read_to_string("/etc/hostname")
+                    .ok()
+                    .map(|h| h.trim().to_string())
+            })
+            .filter(|h| !h.is_empty());
+
+        let cwd_hash = std::env::current_dir()
+            .ok()
+            .map(|dir| hex::encode(sha2::Sha256::digest(dir.to_string_lossy().as_bytes())));
+
+        // Prefer the OS-provided machine ID, which survives hostname changes
+        let machine_id = ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+            .iter()
+            .find_map(|path| std::fs::read_to_string(path).ok())
+            .map(|id| id.trim().to_string())
+            .unwrap_or_else(|| hostname.clone().unwrap_or_default());
+        let machine_fingerprint = hex::encode(sha2::Sha256::digest(
+            format!(
+                "{}:{}:{}",
+                machine_id,
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            )
+            .as_bytes(),
+        ));
+
+        ClientContext {
+            hostname,
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            cwd_hash,
+            machine_fingerprint,
+            message,
+        }
+    }
+}
+
 static KEY: &str = include_str!(concat!(env!("OUT_DIR"), "/key.json"));
 const HOST_INFO: (&str, &str) = match option_env!("RAILWAY_PUBLIC_DOMAIN") {
     Some(domain) => ("https", domain),
@@ -21,8 +77,9 @@ fn main() {
     // Print the key data
     let args: Vec<String> = std::env::args().collect();
     if args.contains(&"--help".to_string()) {
-        println!("Usage: {} [--json] [--help]", args[0]);
+        println!("Usage: {} [--json] [--message <text>] [--help]", args[0]);
         println!("--json: Print the key data as JSON");
+        println!("--message <text>: Attach a message to the notification");
         println!("--help: Print this help message");
         return;
     } else if args.contains(&"--json".to_string()) {
@@ -30,17 +87,18 @@ fn main() {
         return;
     }
 
+    let message = args
+        .iter()
+        .position(|arg| arg == "--message")
+        .and_then(|i| args.get(i + 1))
+        .cloned();
+
     // Check the hash of the value
     let value_hash = sha2::Sha256::digest(key_data.value.as_bytes());
     let hash_match = hex::encode(value_hash) == key_data.value_hash;
 
     if hash_match {
         eprintln!("Value has not been changed since build");
-
-        // Only fail immediately if built in Railway CI
-        if option_env!("RAILWAY_PUBLIC_DOMAIN").is_some() {
-            return;
-        }
     }
 
     let mut token = key_data.value.trim().parse::<u32>();
@@ -49,33 +107,164 @@ fn main() {
         token = forced_token.parse::<u32>();
     }
 
-    match token {
-        Ok(token) => {
-            println!("Token: {:08X}", token);
-            request(token);
-        }
+    let token = match token {
+        Ok(token) => Some(token),
         Err(e) => {
             eprintln!("Token is not a valid u32 integer: {}", e);
             eprintln!("Original Value: {}", key_data.value);
-            return;
+            None
         }
-    }
+    };
+
+    // Un-patched or stripped binaries fall back to authorizing through the browser
+    let Some(token) = token.or_else(device_authorize) else {
+        return;
+    };
+
+    println!("Token: {:08X}", token);
+    request(token, ClientContext::collect(message));
 
     println!("Hash match: {}", hash_match);
 }
 
-fn request(token: u32) {
+#[derive(Deserialize, Debug)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+// Ask the user to approve this binary from their browser session, then poll for the token
+fn device_authorize() -> Option<u32> {
     let client = reqwest::blocking::Client::new();
+
     let response = client
-        .post(format!(
+        .post(format!("{}://{}/device/code", HOST_INFO.0, HOST_INFO.1))
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.json::<DeviceCodeResponse>());
+    let code = match response {
+        Ok(code) => code,
+        Err(e) => {
+            println!("Device authorization request failed: {}", e);
+            return None;
+        }
+    };
+
+    println!();
+    println!(
+        "To authorize this program, visit: {}",
+        code.verification_uri
+    );
+    println!("and enter the code: {}", code.user_code);
+    println!("(or open {})", code.verification_uri_complete);
+    println!();
+
+    let interval = std::time::Duration::from_secs(code.interval.max(1));
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(code.expires_in);
+
+    while std::time::Instant::now() < deadline {
+        std::thread::sleep(interval);
+
+        let response = client
+            .post(format!("{}://{}/device/token", HOST_INFO.0, HOST_INFO.1))
+            .json(&serde_json::json!({ "device_code": code.device_code }))
+            .send();
+
+        let resp = match response {
+            Ok(resp) => resp,
+            Err(e) => {
+                println!("Device token request error: {}", e);
+                return None;
+            }
+        };
+
+        let success = resp.status().is_success();
+        let body = resp.json::<serde_json::Value>().unwrap_or_default();
+
+        if success {
+            return body["token"]
+                .as_str()
+                .and_then(|token| u32::from_str_radix(token.trim_start_matches("0x"), 16).ok());
+        }
+
+        match body["error"].as_str() {
+            Some("authorization_pending") => continue,
+            Some(error) => {
+                println!("Device authorization failed: {}", error);
+                return None;
+            }
+            None => {
+                println!("Unexpected device token response: {}", body);
+                return None;
+            }
+        }
+    }
+
+    println!("Device authorization timed out");
+    None
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+// Trade the embedded token for a short-lived access token
+fn exchange(client: &reqwest::blocking::Client, token: u32) -> Option<TokenResponse> {
+    let key = format!("0x{:08X}", token);
+
+    let response = client
+        .post(format!("{}://{}/token/exchange", HOST_INFO.0, HOST_INFO.1))
+        .json(&serde_json::json!({
+            "grant_type": "preauth",
+            "token": key,
+        }))
+        .send();
+
+    match response {
+        Ok(resp) if resp.status().is_success() => resp.json::<TokenResponse>().ok(),
+        Ok(resp) => {
+            println!("Token exchange failed with status: {}", resp.status());
+            None
+        }
+        Err(e) => {
+            println!("Token exchange error: {}", e);
+            None
+        }
+    }
+}
+
+fn request(token: u32, context: ClientContext) {
+    let client = reqwest::blocking::Client::new();
+
+    // Prefer an access token, falling back to sending the embedded key directly
+    let request = match exchange(&client, token) {
+        Some(credentials) => {
+            println!(
+                "Access token obtained (expires in {}s)",
+                credentials.expires_in
+            );
+            client
+                .post(format!("{}://{}/notify", HOST_INFO.0, HOST_INFO.1))
+                .bearer_auth(credentials.access_token)
+        }
+        None => client.post(format!(
             "{}://{}/notify?key=0x{:08X}",
             HOST_INFO.0, HOST_INFO.1, token
-        ))
-        .send();
+        )),
+    };
+    let response = request.json(&context).send();
 
     match response {
         Ok(resp) => {
-            if resp.status().is_success() {
+            if resp.status() == reqwest::StatusCode::ACCEPTED {
+                println!("No browser tab is open, the notification will be shown when one is");
+            } else if resp.status().is_success() {
                 println!("Request successful");
             } else {
                 println!("Request failed with status: {}", resp.status());
@@ -1,10 +1,10 @@
 import Badge from "@/components/Badge";
 import DownloadButton from "@/components/DownloadButton";
 import Emboldened from "@/components/Emboldened";
-import useSocket from "@/components/useSocket";
+import useSocket, { type ClientContext } from "@/components/useSocket";
 import { useTabCoordination } from "@/components/useTabCoordination";
-import { cn, plural, toHex, type ClassValue } from "@/util";
-import { useRef, useState } from "react";
+import { cn, plural, toHex, withBackend, type ClassValue } from "@/util";
+import { useEffect, useRef, useState } from "react";
 
 type DemoProps = {
   class?: ClassValue;
@@ -13,8 +13,28 @@ type DemoProps = {
 const Demo = ({ class: className }: DemoProps) => {
   const { tryPlayAudio } = useTabCoordination();
 
-  const { id, downloads, executables, deleteDownload, buildLog } = useSocket({
-    notify: (token) => {
+  const {
+    id,
+    downloads,
+    quota,
+    executables,
+    deleteDownload,
+    resetMachineBinding,
+    buildLog,
+    approveDeviceCode,
+    setDownloadLabel,
+    setDownloadNotes,
+    setDownloadTags,
+    pairingCode,
+    requestPairingCode,
+    submitPairingCode,
+    clearDownloads,
+    error,
+    dismissError,
+  } = useSocket({
+    notify: (token, context, label, queuedAt, machineMismatch) => {
+      if (context != null)
+        setLastContext({ token, label, context, queuedAt, machineMismatch });
       // Create fresh audio element for each notification to avoid browser playback state issues
       // Reusing the same element can cause the audio indicator to show without sound
       const audio = new Audio("/notify.wav");
@@ -29,8 +49,62 @@ const Demo = ({ class: className }: DemoProps) => {
   });
   // TODO: Toasts
 
+  // Approve a device that sent the user here with its user code
+  useEffect(() => {
+    if (id == null) return;
+
+    const params = new URLSearchParams(window.location.search);
+    const userCode = params.get("user_code");
+    if (userCode == null) return;
+
+    if (window.confirm(`Authorize the device showing code ${userCode}?`))
+      approveDeviceCode(userCode);
+
+    params.delete("user_code");
+    const query = params.toString();
+    window.history.replaceState(
+      null,
+      "",
+      window.location.pathname + (query ? `?${query}` : "")
+    );
+  }, [id]);
+
   const [highlightedToken, setHighlightedToken] = useState<number | null>(null);
+  const [lastContext, setLastContext] = useState<{
+    token: number;
+    label: string | null;
+    context: ClientContext;
+    queuedAt: string | null;
+    machineMismatch: boolean;
+  } | null>(null);
   const highlightedTimeoutRef = useRef<NodeJS.Timeout | null>(null);
+  const importInputRef = useRef<HTMLInputElement | null>(null);
+
+  // Restore downloads from a previously exported session; the new state arrives over the socket
+  async function importSession(file: File) {
+    const response = await fetch(withBackend("/session/import"), {
+      method: "POST",
+      credentials: "include",
+      headers: { "Content-Type": "application/json" },
+      body: await file.text(),
+    });
+
+    if (!response.ok)
+      window.alert(`Unable to import session: ${await response.text()}`);
+  }
+
+  // Downloads the server would refuse for exceeding the session's quota
+  const quotaExhausted =
+    quota != null &&
+    ((quota.limits.max_live != null && quota.live >= quota.limits.max_live) ||
+      (quota.limits.max_per_hour != null &&
+        quota.last_hour >= quota.limits.max_per_hour) ||
+      (quota.limits.max_bytes != null &&
+        executables != null &&
+        executables.every(
+          (executable) =>
+            quota.bytes_served + executable.size > quota.limits.max_bytes!
+        )));
 
   function highlight(token: number) {
     setHighlightedToken(token);
@@ -53,19 +127,29 @@ const Demo = ({ class: className }: DemoProps) => {
         session.
         <br />
         Your session is{" "}
-        <Emboldened skeletonWidth="0x12345678" copyable={true}>
-          {id != null ? toHex(id) : null}
+        <Emboldened
+          skeletonWidth="0123456789abcdef0123456789abcdef"
+          copyable={true}
+        >
+          {id}
         </Emboldened>
         . You have{" "}
         <Emboldened className="text-teal-400 font-inter">
           {downloads?.length ?? null}
         </Emboldened>{" "}
         known {plural("download", downloads?.length ?? 0)}.
+        {quotaExhausted ? (
+          <>
+            <br />
+            This session has reached its download limit. Delete a download or
+            try again later.
+          </>
+        ) : null}
       </p>
       <div className="flex flex-wrap justify-center gap-y-2.5 gap-x-2">
         <DownloadButton
           key="download"
-          disabled={executables == null}
+          disabled={executables == null || quotaExhausted}
           buildLog={buildLog}
           executables={executables}
         />
@@ -77,16 +161,175 @@ const Demo = ({ class: className }: DemoProps) => {
               {
                 "bg-zinc-500 animate-pulse-border border-zinc-300 text-zinc-50":
                   highlightedToken === download.token,
+                "line-through text-zinc-400": download.revoked_at != null,
               }
             )}
             onClick={() => {
               deleteDownload(download.token);
             }}
           >
-            {toHex(download.token)}
+            <span
+              className="cursor-text"
+              title={[
+                download.notes,
+                download.tags.length > 0 ? `#${download.tags.join(" #")}` : null,
+                ...download.usage.map(
+                  (event) =>
+                    `${event.timestamp} ${event.outcome} ${event.remote_ip ?? ""}`
+                ),
+              ]
+                .filter((line) => line != null)
+                .join("\n")}
+              onDoubleClick={() => {
+                const label = window.prompt(
+                  `Label for ${toHex(download.token)}`,
+                  download.label ?? ""
+                );
+                if (label != null) setDownloadLabel(download.token, label);
+              }}
+            >
+              {download.label ?? toHex(download.token)}
+            </span>
+            <button
+              type="button"
+              className="ms-1.5 text-xs text-zinc-400 underline hover:text-zinc-200"
+              title="Notes about this download"
+              onClick={(event) => {
+                event.stopPropagation();
+                const notes = window.prompt(
+                  `Notes for ${toHex(download.token)}`,
+                  download.notes ?? ""
+                );
+                if (notes != null) setDownloadNotes(download.token, notes);
+              }}
+            >
+              Notes
+            </button>
+            <button
+              type="button"
+              className="ms-1.5 text-xs text-zinc-400 underline hover:text-zinc-200"
+              title="Comma-separated tags for this download"
+              onClick={(event) => {
+                event.stopPropagation();
+                const tags = window.prompt(
+                  `Tags for ${toHex(download.token)}, separated by commas`,
+                  download.tags.join(", ")
+                );
+                if (tags != null)
+                  setDownloadTags(
+                    download.token,
+                    tags.split(",").filter((tag) => tag.trim() !== "")
+                  );
+              }}
+            >
+              Tags
+            </button>
+            {download.machine_fingerprint != null && (
+              <button
+                type="button"
+                className="ms-1.5 text-xs text-zinc-400 underline hover:text-zinc-200"
+                title="This download is bound to the first machine it ran on"
+                onClick={() => resetMachineBinding(download.token)}
+              >
+                Unbind
+              </button>
+            )}
           </Badge>
         ))}
       </div>
+      {lastContext != null && (
+        <p className="mt-3 mb-0 text-sm text-zinc-400">
+          <Emboldened className="text-teal-400">
+            {lastContext.label ?? toHex(lastContext.token)}
+          </Emboldened>{" "}
+          ran on {lastContext.context.hostname ?? "an unknown host"} (
+          {lastContext.context.os ?? "?"}/{lastContext.context.arch ?? "?"}
+          {lastContext.context.client_version != null &&
+            `, v${lastContext.context.client_version}`}
+          ){lastContext.context.message != null &&
+            `: "${lastContext.context.message}"`}
+          {lastContext.queuedAt != null &&
+            ` while no tab was open (${new Date(
+              lastContext.queuedAt
+            ).toLocaleTimeString()})`}
+          {lastContext.machineMismatch && (
+            <span className="text-amber-400">
+              {" "}
+              - not the machine this download is bound to
+            </span>
+          )}
+        </p>
+      )}
+      {error != null && (
+        <p className="mt-3 mb-0 text-sm text-red-400">
+          {error.message}{" "}
+          <button className="underline" onClick={dismissError}>
+            Dismiss
+          </button>
+        </p>
+      )}
+      {id != null && (
+        <p className="mt-3 mb-0 text-sm text-zinc-400">
+          {pairingCode != null ? (
+            <>
+              Enter{" "}
+              <Emboldened className="text-teal-400" copyable={true}>
+                {pairingCode.code}
+              </Emboldened>{" "}
+              in another browser to share this session with it.
+            </>
+          ) : (
+            <button className="underline" onClick={requestPairingCode}>
+              Pair another browser
+            </button>
+          )}{" "}
+          <button
+            className="underline"
+            onClick={() => {
+              const code = window.prompt(
+                "Enter the pairing code shown in your other browser"
+              );
+              if (code != null && code.trim() !== "")
+                submitPairingCode(code.trim());
+            }}
+          >
+            Have a pairing code?
+          </button>{" "}
+          <a className="underline" href={withBackend("/session/export")}>
+            Export session
+          </a>{" "}
+          <button
+            className="underline"
+            onClick={() => importInputRef.current?.click()}
+          >
+            Import session
+          </button>
+          {downloads != null && downloads.length > 0 && (
+            <>
+              <button
+                className="underline"
+                onClick={() => {
+                  if (window.confirm("Revoke every download in this session?"))
+                    clearDownloads().catch((e) => console.error(e));
+                }}
+              >
+                Revoke all
+              </button>{" "}
+            </>
+          )}
+          <input
+            ref={importInputRef}
+            type="file"
+            accept="application/json"
+            className="hidden"
+            onChange={(event) => {
+              const file = event.target.files?.[0];
+              if (file != null) importSession(file);
+              event.target.value = "";
+            }}
+          />
+        </p>
+      )}
       <div className="mt-4 p-2 bg-zinc-900/90 rounded-md border border-zinc-700">
         <p className="my-0">
           The server running this is completely ephemeral, can restart at any
@@ -1,12 +1,87 @@
 import { withBackend } from "@/util";
-import { useEffect, useState } from "react";
+import { useEffect, useRef, useState } from "react";
 import useWebSocket, { ReadyState } from "react-use-websocket";
 
+export interface ClientContext {
+  hostname: string | null;
+  os: string | null;
+  arch: string | null;
+  client_version: string | null;
+  cwd_hash: string | null;
+  machine_fingerprint: string | null;
+  message: string | null;
+}
+
+export type UsageOutcome =
+  | "delivered"
+  | "queued"
+  | "failed"
+  | "revoked"
+  | "expired"
+  | "machine-mismatch";
+
+export interface UsageEvent {
+  timestamp: string;
+  remote_ip: string | null;
+  user_agent: string | null;
+  outcome: UsageOutcome;
+  machine_mismatch: boolean;
+  context: ClientContext | null;
+}
+
 export interface Download {
   token: number;
   filename: string;
   last_used: string;
   download_time: string;
+  expires_at: string | null;
+  machine_fingerprint: string | null;
+  usage: UsageEvent[];
+  label: string | null;
+  notes: string | null;
+  tags: string[];
+  revoked_at: string | null;
+}
+
+export interface QuotaUsage {
+  live: number;
+  last_hour: number;
+  bytes_served: number;
+  limits: {
+    max_live: number | null;
+    max_per_hour: number | null;
+    max_bytes: number | null;
+  };
+}
+
+export interface PairingCode {
+  code: string;
+  expires_at: string;
+}
+
+// What a command sent with a request ID accomplished
+export type CommandResult =
+  | { kind: "done" }
+  | { kind: "reservation"; token: number; url: string; expires_at: string }
+  | { kind: "cleared"; count: number };
+
+// Stable codes for why the server refused a message
+export type ErrorCode =
+  | "malformed_message"
+  | "unknown_command"
+  | "unexpected_hello"
+  | "invalid_payload"
+  | "not_found"
+  | "invalid_code"
+  | "quota_exceeded"
+  | "pairing_failed"
+  | "internal";
+
+export class CommandError extends Error {
+  constructor(public code: ErrorCode, message: string) {
+    super(message);
+    this.name = "CommandError";
+  }
 }
 
 export interface Executable {
@@ -16,15 +91,51 @@ export interface Executable {
 }
 
 export interface UseSocketResult {
-  id: number | null;
+  id: string | null;
+  server: ServerInfo | null;
   executables: Executable[] | null;
   downloads: Download[] | null;
+  quota: QuotaUsage | null;
+  pairingCode: PairingCode | null;
   buildLog: string | null;
+  // The last refusal of a command that wasn't sent with a request ID
+  error: CommandError | null;
+  dismissError: () => void;
   deleteDownload: (id: number) => void;
+  resetMachineBinding: (id: number) => void;
+  approveDeviceCode: (userCode: string) => void;
+  setDownloadLabel: (id: number, label: string | null) => void;
+  setDownloadNotes: (id: number, notes: string | null) => void;
+  setDownloadTags: (id: number, tags: string[]) => void;
+  requestPairingCode: () => void;
+  submitPairingCode: (code: string) => void;
+  refreshState: () => Promise<CommandResult>;
+  reserveDownload: (executable: string) => Promise<CommandResult>;
+  renameDownload: (id: number, filename: string) => Promise<CommandResult>;
+  revokeToken: (id: number) => Promise<CommandResult>;
+  clearDownloads: () => Promise<CommandResult>;
 }
 
 export interface UseSocketProps {
-  notify?: (token: number) => void;
+  notify?: (
+    token: number,
+    context: ClientContext | null,
+    label: string | null,
+    // When the alert was queued, if it was raised while no tab was open
+    queuedAt: string | null,
+    // Whether the binary ran on a different machine than the download is bound to
+    machineMismatch: boolean
+  ) => void;
+}
+
+// The WebSocket protocol version this client speaks, negotiated with the server on connect
+const PROTOCOL_VERSION = 3;
+// Matches the server, which only keeps the most recent uses of each token
+const MAX_USAGE_EVENTS = 32;
+
+export interface ServerInfo {
+  name: string;
+  version: string;
 }
 
 export type Status =
@@ -34,24 +145,62 @@ export type Status =
   | "closed"
   | "uninstantiated";
 
+// What the client held when its connection dropped, so a reconnect can pick up from there
+interface ResumePoint {
+  seq: number;
+  id: string;
+  downloads: Download[];
+  quota: QuotaUsage | null;
+}
+
 function useSocket({ notify }: UseSocketProps): UseSocketResult {
+  const resume = useRef<ResumePoint | null>(null);
+
   const { sendMessage, lastMessage, readyState } = useWebSocket(
     withBackend(
       window.location.protocol === "https:" ? "wss://" : "ws://",
-      "/ws"
+      // Announces the hello sent on open, so the server waits for it
+      "/ws?hello"
     ),
     {
       shouldReconnect: () => true,
+      onOpen: (event) => {
+        (event.target as WebSocket).send(
+          JSON.stringify({
+            type: "hello",
+            version: PROTOCOL_VERSION,
+            last_seq: resume.current?.seq,
+          })
+        );
+      },
     }
   );
 
-  const [id, setId] = useState<number | null>(null);
+  const [id, setId] = useState<string | null>(null);
+  const [server, setServer] = useState<ServerInfo | null>(null);
   const [downloads, setDownloads] = useState<Download[] | null>(null);
+  const [quota, setQuota] = useState<QuotaUsage | null>(null);
+  const [pairingCode, setPairingCode] = useState<PairingCode | null>(null);
   const [executables, setExecutables] = useState<{
     build_log: string | null;
     executables: Executable[];
   } | null>(null);
 
+  // Commands awaiting a response, keyed by request ID
+  const pending = useRef(
+    new Map<
+      string,
+      {
+        resolve: (result: CommandResult) => void;
+        reject: (error: CommandError) => void;
+      }
+    >()
+  );
+  const [error, setError] = useState<CommandError | null>(null);
+  // The sequence number of the last change applied, to spot events that went missing
+  const seq = useRef<number | null>(null);
+  const nextRequestId = useRef(0);
+
   const connectionStatus: Status = {
     [ReadyState.CONNECTING]: "connecting",
     [ReadyState.OPEN]: "open",
@@ -62,9 +211,17 @@ function useSocket({ notify }: UseSocketProps): UseSocketResult {
 
   useEffect(() => {
     if (connectionStatus === "closing" || connectionStatus === "closed") {
+      if (seq.current != null && id != null && downloads != null)
+        resume.current = { seq: seq.current, id, downloads, quota };
       setId(null);
+      setServer(null);
       setDownloads(null);
+      setQuota(null);
+      setPairingCode(null);
       setExecutables(null);
+      // Commands sent on a closed socket will never be answered
+      pending.current.clear();
+      seq.current = null;
     }
   }, [readyState]);
 
@@ -77,13 +234,81 @@ function useSocket({ notify }: UseSocketProps): UseSocketResult {
         throw new Error("Received message without type");
 
       switch (data.type) {
+        case "hello":
+          setServer(data.server as ServerInfo);
+          break;
+        case "hello-rejected":
+          console.error("Server rejected this client's protocol", data.reason);
+          break;
         case "notify":
+          // Alerts replayed after a full state are already accounted for in it
+          if (seq.current != null && data.seq === seq.current + 1)
+            seq.current = data.seq as number;
           const token = data.token as number;
-          if (notify != null) notify(token);
+          const context = (data.context ?? null) as ClientContext | null;
+          const label = (data.label ?? null) as string | null;
+          const queuedAt = (data.queued_at ?? null) as string | null;
+          const machineMismatch = (data.machine_mismatch ?? false) as boolean;
+          if (notify != null)
+            notify(token, context, label, queuedAt, machineMismatch);
+          break;
+        case "download-added":
+        case "download-removed":
+        case "download-updated":
+        case "token-used":
+          if (seq.current == null || data.seq !== seq.current + 1) {
+            // A change was missed, so the local copy can't be patched; start over
+            seq.current = null;
+            sendMessage(JSON.stringify({ type: "refresh-state" }));
+            break;
+          }
+          seq.current = data.seq as number;
+          applyChange(data);
+          break;
+        case "resumed":
+          // The server will replay what was missed on top of what was held before
+          if (resume.current != null && resume.current.seq === data.seq) {
+            seq.current = resume.current.seq;
+            setId(resume.current.id);
+            setDownloads(resume.current.downloads);
+            setQuota(resume.current.quota);
+          } else {
+            sendMessage(JSON.stringify({ type: "refresh-state" }));
+          }
+          resume.current = null;
           break;
         case "state":
-          setId(data.session.id as number);
+          resume.current = null;
+          seq.current = data.session.seq as number;
+          setId(data.session.id as string);
           setDownloads(data.session.downloads as Download[]);
+          setQuota(data.quota as QuotaUsage);
+          break;
+        case "response": {
+          const command = pending.current.get(data.request_id);
+          if (command != undefined) {
+            pending.current.delete(data.request_id);
+            command.resolve(data.result as CommandResult);
+          }
+          break;
+        }
+        case "error": {
+          const error = new CommandError(data.code as ErrorCode, data.message);
+          const command =
+            data.request_id != null
+              ? pending.current.get(data.request_id)
+              : undefined;
+          if (command != undefined) {
+            pending.current.delete(data.request_id);
+            command.reject(error);
+          } else {
+            console.error("Server refused a message", data.code, data.message);
+            setError(error);
+          }
+          break;
+        }
+        case "pairing-code":
+          setPairingCode({ code: data.code, expires_at: data.expires_at });
           break;
         case "executables":
           setExecutables({
@@ -108,12 +333,188 @@ function useSocket({ notify }: UseSocketProps): UseSocketResult {
     );
   }
 
+  function resetMachineBinding(download_token: number) {
+    if (readyState !== WebSocket.OPEN) return;
+
+    sendMessage(
+      JSON.stringify({
+        type: "reset-machine-binding",
+        id: download_token,
+      })
+    );
+  }
+
+  function approveDeviceCode(userCode: string) {
+    if (readyState !== WebSocket.OPEN) return;
+
+    sendMessage(
+      JSON.stringify({
+        type: "approve-device-code",
+        user_code: userCode,
+      })
+    );
+  }
+
+  function setDownloadLabel(download_token: number, label: string | null) {
+    if (readyState !== WebSocket.OPEN) return;
+
+    sendMessage(
+      JSON.stringify({
+        type: "set-download-label",
+        id: download_token,
+        label,
+      })
+    );
+  }
+
+  function setDownloadNotes(download_token: number, notes: string | null) {
+    if (readyState !== WebSocket.OPEN) return;
+
+    sendMessage(
+      JSON.stringify({
+        type: "set-download-notes",
+        id: download_token,
+        notes,
+      })
+    );
+  }
+
+  function setDownloadTags(download_token: number, tags: string[]) {
+    if (readyState !== WebSocket.OPEN) return;
+
+    sendMessage(
+      JSON.stringify({
+        type: "set-download-tags",
+        id: download_token,
+        tags,
+      })
+    );
+  }
+
+  function requestPairingCode() {
+    if (readyState !== WebSocket.OPEN) return;
+
+    sendMessage(JSON.stringify({ type: "request-pairing-code" }));
+  }
+
+  function submitPairingCode(code: string) {
+    if (readyState !== WebSocket.OPEN) return;
+
+    sendMessage(
+      JSON.stringify({
+        type: "submit-pairing-code",
+        code,
+      })
+    );
+  }
+
+  // Patches the local downloads with an incremental change from the server
+  function applyChange(data: any) {
+    switch (data.type) {
+      case "download-added":
+        setDownloads((downloads) => [
+          ...(downloads ?? []),
+          data.download as Download,
+        ]);
+        setQuota(data.quota as QuotaUsage);
+        break;
+      case "download-removed":
+        setDownloads(
+          (downloads) =>
+            downloads?.filter((download) => download.token !== data.token) ??
+            null
+        );
+        setQuota(data.quota as QuotaUsage);
+        break;
+      case "download-updated":
+        setDownloads(
+          (downloads) =>
+            downloads?.map((download) =>
+              download.token === data.download.token
+                ? (data.download as Download)
+                : download
+            ) ?? null
+        );
+        break;
+      case "token-used":
+        setDownloads(
+          (downloads) =>
+            downloads?.map((download) =>
+              download.token === data.token
+                ? {
+                    ...download,
+                    last_used: data.last_used as string,
+                    usage: [
+                      ...download.usage,
+                      data.usage as UsageEvent,
+                    ].slice(-MAX_USAGE_EVENTS),
+                  }
+                : download
+            ) ?? null
+        );
+        break;
+    }
+  }
+
+  // Sends a command tagged with a request ID, resolving once the server responds to it
+  function sendCommand(message: object): Promise<CommandResult> {
+    if (readyState !== WebSocket.OPEN)
+      return Promise.reject(new Error("Not connected"));
+
+    const request_id = String(++nextRequestId.current);
+    return new Promise((resolve, reject) => {
+      pending.current.set(request_id, { resolve, reject });
+      sendMessage(JSON.stringify({ ...message, request_id }));
+    });
+  }
+
+  function refreshState() {
+    return sendCommand({ type: "refresh-state" });
+  }
+
+  function reserveDownload(executable: string) {
+    return sendCommand({ type: "reserve-download", executable });
+  }
+
+  function renameDownload(download_token: number, filename: string) {
+    return sendCommand({
+      type: "rename-download",
+      id: download_token,
+      filename,
+    });
+  }
+
+  function revokeToken(download_token: number) {
+    return sendCommand({ type: "revoke-token", id: download_token });
+  }
+
+  function clearDownloads() {
+    return sendCommand({ type: "clear-downloads" });
+  }
+
   return {
     id,
+    server,
     downloads,
+    quota,
+    pairingCode,
     executables: executables?.executables ?? null,
     buildLog: executables?.build_log ?? null,
+    error,
+    dismissError: () => setError(null),
     deleteDownload,
+    resetMachineBinding,
+    approveDeviceCode,
+    setDownloadLabel,
+    setDownloadNotes,
+    setDownloadTags,
+    requestPairingCode,
+    submitPairingCode,
+    refreshState,
+    reserveDownload,
+    renameDownload,
+    revokeToken,
+    clearDownloads,
   };
 }
 